@@ -0,0 +1,446 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+
+use sonr::Token;
+use sonr::reactor::{Reactor, Reaction};
+use sonr::errors::Result;
+use sonr::sync::signal::ReactiveSignalReceiver;
+use bytes::{Bytes, BytesMut, BufMut};
+
+use crate::broadcast::{BoundedBroadcast, BroadcastReceiver, Lag};
+use crate::codec::{Codec, LineCodec};
+use crate::messages::{AckMessage, Confirm, Metadata, PubMessage, ServerMessage};
+use crate::options::Backpressure;
+use crate::subscriptions::Interest;
+
+// -----------------------------------------------------------------------------
+//     - Published messages -
+//     Messages are framed for delivery once, by the publisher.
+//     Each message in a batch is:
+//     channel len u16 BE | channel | frame len u32 BE | frame | tagged len u32 BE | tagged
+//     The frame is the `PubMessage` as a JSON line (subscriber port) and the tagged
+//     frame the same message as a `ServerMessage::Message` (client port).
+//     The tagged frame is empty unless the broadcast is used by a client port,
+//     see `BoundedBroadcast::with_tagged_frames`.
+// -----------------------------------------------------------------------------
+
+// `ServerMessage::Message` is the `PubMessage` with the tag as the first field
+const MESSAGE_TAG: &[u8] = br#"{"op":"msg","#;
+
+/// Messages waiting to be published on the broadcast.
+/// Messages are buffered until the batch is full (or a timer tick)
+/// rather than publishing every message on its own.
+///
+/// While the broadcast is full publishes are either nacked or, with
+/// `Backpressure::Pause`, held back until there is room (see `resume`).
+pub struct PublishBatch {
+    broadcast: BoundedBroadcast,
+    threshold: usize,
+    backpressure: Backpressure,
+    payload: BytesMut,
+    // Connections in confirm mode: acks are held back
+    // until the message has been handed to the broadcast.
+    confirm: HashSet<Token>,
+    pending_acks: Vec<(Token, AckMessage)>,
+    // Publishes (and the confirms sent after them) held back
+    // while the broadcast is full, in the order they were received
+    held: HashMap<Token, VecDeque<Held>>,
+}
+
+enum Held {
+    Publish(u64, PubMessage),
+    Confirm(Confirm),
+}
+
+impl PublishBatch {
+    pub fn new(broadcast: BoundedBroadcast, threshold: usize, backpressure: Backpressure) -> Self {
+        Self {
+            broadcast,
+            threshold,
+            backpressure,
+            payload: BytesMut::with_capacity(threshold * 2),
+            confirm: HashSet::new(),
+            pending_acks: Vec::new(),
+            held: HashMap::new(),
+        }
+    }
+
+    /// Stamp the message with the broker metadata and add it to the batch.
+    /// Returns the reply to send right away, if any.
+    /// In confirm mode the ack is held back until the batch is published,
+    /// so acks can arrive out of order (hence the id).
+    pub fn push(&mut self, connection_id: Token, publisher: u64, message: PubMessage) -> Option<AckMessage> {
+        let full = self.broadcast.is_full();
+        if full && self.backpressure == Backpressure::Nack {
+            return Some(AckMessage::nack(message.id, "broker overloaded"));
+        }
+
+        // Messages received after a held back one are held back too
+        if full || self.is_held(connection_id) {
+            self.held.entry(connection_id).or_default().push_back(Held::Publish(publisher, message));
+            return None
+        }
+
+        let ack = match self.put(publisher, message) {
+            Ok(ack) => ack,
+            Err(nack) => return Some(nack),
+        };
+
+        if self.confirm.contains(&connection_id) {
+            self.pending_acks.push((connection_id, ack));
+            None
+        } else {
+            Some(ack)
+        }
+    }
+
+    // Add the message to the payload, returning its ack
+    fn put(&mut self, publisher: u64, mut message: PubMessage) -> std::result::Result<AckMessage, AckMessage> {
+        message.metadata = Some(Metadata::new(publisher));
+
+        if self.payload.remaining_mut() < self.threshold {
+            self.payload.reserve(self.threshold);
+        }
+
+        put_message(&mut self.payload, &message, self.broadcast.tagged_frames())?;
+        Ok(AckMessage::ack(message.id))
+    }
+
+    /// Switch confirm mode, returning the ack to send right away.
+    /// A confirm sent after a held back publish is held back with it.
+    pub fn confirm(&mut self, connection_id: Token, confirm: Confirm) -> Option<AckMessage> {
+        if let Some(held) = self.held.get_mut(&connection_id) {
+            held.push_back(Held::Confirm(confirm));
+            return None
+        }
+
+        self.set_confirm(connection_id, confirm.confirm);
+        Some(AckMessage::ack(confirm.id))
+    }
+
+    fn set_confirm(&mut self, connection_id: Token, confirm: bool) {
+        if confirm {
+            self.confirm.insert(connection_id);
+        } else {
+            self.confirm.remove(&connection_id);
+        }
+    }
+
+    /// The connection has publishes held back, it is
+    /// not read again until they are released on `resume`
+    pub fn is_held(&self, connection_id: Token) -> bool {
+        self.held.contains_key(&connection_id)
+    }
+
+    /// Once the broadcast has room again, add the held back messages
+    /// to the batch. Their acks are sent along with the next `publish`.
+    /// Returns the connections that were held back.
+    pub fn resume(&mut self) -> Vec<Token> {
+        if self.held.is_empty() || self.broadcast.is_full() {
+            return Vec::new()
+        }
+
+        let held = std::mem::take(&mut self.held);
+        let mut connection_ids = Vec::new();
+        for (connection_id, messages) in held {
+            for message in messages {
+                match message {
+                    Held::Publish(publisher, message) => {
+                        let ack = self.put(publisher, message).unwrap_or_else(|nack| nack);
+                        self.pending_acks.push((connection_id, ack));
+                    }
+                    Held::Confirm(confirm) => {
+                        self.set_confirm(connection_id, confirm.confirm);
+                        self.pending_acks.push((connection_id, AckMessage::ack(confirm.id)));
+                    }
+                }
+            }
+            connection_ids.push(connection_id);
+        }
+        connection_ids
+    }
+
+    /// Enough data is buffered to publish the batch
+    pub fn is_full(&self) -> bool {
+        self.payload.len() >= self.threshold
+    }
+
+    /// Publish the buffered messages, returning the acks
+    /// that were waiting for them to be published.
+    /// Note: once messages are persisted the acks should
+    /// wait for the fsync rather than the broadcast.
+    pub fn publish(&mut self) -> Vec<(Token, AckMessage)> {
+        // Only publish if we have actual data
+        if !self.payload.is_empty() {
+            self.broadcast.publish(self.payload.take().freeze());
+        }
+
+        self.pending_acks.drain(..).collect()
+    }
+
+    /// Forget a disconnected connection.
+    /// Tokens are reused, so any held back acks are dropped
+    /// rather than sent to the next connection with the same token.
+    /// Held back publishes are dropped too, as they would have been
+    /// had they been left in the socket.
+    pub fn remove(&mut self, connection_id: Token) {
+        self.confirm.remove(&connection_id);
+        self.held.remove(&connection_id);
+        self.pending_acks.retain(|(cid, _)| *cid != connection_id);
+    }
+}
+
+/// Frame a message for delivery and add it to the payload,
+/// see the record format above.
+/// Returns the nack if the message can't be published.
+pub(crate) fn put_message(payload: &mut BytesMut, message: &PubMessage, tagged: bool) -> std::result::Result<(), AckMessage> {
+    if message.channel.len() > u16::MAX as usize {
+        let reason = format!("channel exceeds {} bytes", u16::MAX);
+        return Err(AckMessage::nack(message.id.clone(), reason));
+    }
+
+    let frame = match LineCodec::encode(message) {
+        Ok(frame) => frame,
+        Err(e) => return Err(AckMessage::nack(message.id.clone(), e.to_string())),
+    };
+
+    // Skip the opening brace of the frame, it's part of the tag
+    let tagged_len = if tagged { MESSAGE_TAG.len() + frame.len() - 1 } else { 0 };
+    let len = 2 + message.channel.len() + 4 + frame.len() + 4 + tagged_len;
+    if len > payload.remaining_mut() {
+        payload.reserve(len);
+    }
+
+    payload.put_slice(&(message.channel.len() as u16).to_be_bytes());
+    payload.put_slice(message.channel.as_bytes());
+    payload.put_slice(&(frame.len() as u32).to_be_bytes());
+    payload.put_slice(&frame);
+    payload.put_slice(&(tagged_len as u32).to_be_bytes());
+    if tagged {
+        payload.put_slice(MESSAGE_TAG);
+        payload.put_slice(&frame[1..]);
+    }
+    Ok(())
+}
+
+/// Receiving end of the broadcast,
+/// splitting the published batches into messages.
+pub struct BatchReceiver {
+    messages: ReactiveSignalReceiver<Bytes>,
+    lag: Arc<Lag>,
+    interest: Interest,
+    batches: VecDeque<Bytes>,
+}
+
+impl BatchReceiver {
+    pub fn new(messages: BroadcastReceiver) -> Result<Self> {
+        let (messages, lag, interest) = messages.into_parts();
+        Ok(Self {
+            messages: ReactiveSignalReceiver::new(messages)?,
+            lag,
+            interest,
+            batches: VecDeque::new(),
+        })
+    }
+
+    pub fn token(&self) -> Token {
+        self.messages.token()
+    }
+
+    /// The channels to receive messages for,
+    /// kept up to date by the reactor's `Subscriptions`
+    pub fn interest(&self) -> Interest {
+        self.interest.clone()
+    }
+
+    /// Receive every published batch.
+    /// Returns false if there was nothing to receive.
+    pub fn receive(&mut self, reaction: Reaction<()>) -> bool {
+        if let Reaction::Value(batch) = self.messages.react(reaction) {
+            self.add_batch(batch);
+
+            // Keep "reacting" until we no longer receive a message
+            while let Reaction::Value(batch) = self.messages.react(Reaction::Continue) {
+                self.add_batch(batch);
+            }
+
+            return true
+        }
+        false
+    }
+
+    /// The next message of the received batches
+    pub fn next_message(&mut self) -> Option<Published> {
+        loop {
+            let batch = self.batches.front_mut()?;
+            match Published::split(batch) {
+                Some(published) => return Some(published),
+                // End of the batch (anything left over is malformed)
+                None => { self.batches.pop_front(); }
+            }
+        }
+    }
+
+    fn add_batch(&mut self, batch: Bytes) {
+        self.lag.received(batch.len());
+        self.batches.push_back(batch);
+    }
+}
+
+/// A message as published on the broadcast, framed for delivery.
+/// Every part is a slice of the shared batch.
+pub struct Published {
+    channel: Bytes,
+    frame: Bytes,
+    tagged: Bytes,
+}
+
+impl Published {
+    // Split the next message off the front of a batch
+    pub(crate) fn split(batch: &mut Bytes) -> Option<Self> {
+        let channel_len = split_length(batch, 2)?;
+        let channel = split_bytes(batch, channel_len)?;
+        let frame_len = split_length(batch, 4)?;
+        let frame = split_bytes(batch, frame_len)?;
+        let tagged_len = split_length(batch, 4)?;
+        let tagged = split_bytes(batch, tagged_len)?;
+
+        Some(Self { channel, frame, tagged })
+    }
+
+    pub fn channel(&self) -> &str {
+        std::str::from_utf8(&self.channel).unwrap_or_default()
+    }
+
+    /// The message framed for the subscriber port.
+    /// Only codecs other than JSON lines have to re-encode it.
+    pub fn encode<C: Codec>(&self) -> Option<Bytes> {
+        if C::JSON_LINES {
+            return Some(self.frame.clone());
+        }
+
+        self.message().and_then(|message| C::encode(&message).ok())
+    }
+
+    /// The message framed for the client port, see `encode`
+    pub fn encode_tagged<C: Codec>(&self) -> Option<Bytes> {
+        if C::JSON_LINES && !self.tagged.is_empty() {
+            return Some(self.tagged.clone());
+        }
+
+        self.message().and_then(|message| C::encode(&ServerMessage::Message(message)).ok())
+    }
+
+    pub fn message(&self) -> Option<PubMessage> {
+        serde_json::from_slice(&self.frame).ok()
+    }
+}
+
+fn split_length(batch: &mut Bytes, width: usize) -> Option<usize> {
+    let bytes = split_bytes(batch, width)?;
+    Some(bytes.iter().fold(0, |len, b| len << 8 | *b as usize))
+}
+
+fn split_bytes(batch: &mut Bytes, len: usize) -> Option<Bytes> {
+    if batch.len() < len {
+        return None;
+    }
+    Some(batch.split_to(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn published(tagged: bool) -> Published {
+        let mut payload = BytesMut::new();
+        put_message(&mut payload, &PubMessage::new("orders", "hi"), tagged).unwrap();
+        let mut batch = payload.freeze();
+        let published = Published::split(&mut batch).unwrap();
+        assert!(batch.is_empty());
+        published
+    }
+
+    fn message(id: &str, channel: &str) -> PubMessage {
+        let mut message = PubMessage::new(channel, "hi");
+        message.id = Some(id.into());
+        message
+    }
+
+    fn tagged_message(frame: &Bytes) -> PubMessage {
+        match serde_json::from_slice(frame).unwrap() {
+            ServerMessage::Message(message) => message,
+            message => panic!("unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn tagged_frame() {
+        let published = published(true);
+        assert_eq!(published.channel(), "orders");
+        assert!(!published.tagged.is_empty());
+
+        let message = tagged_message(&published.encode_tagged::<LineCodec>().unwrap());
+        assert_eq!(message.channel, "orders");
+        assert_eq!(&message.payload[..], b"hi");
+    }
+
+    #[test]
+    fn without_tagged_frame() {
+        let published = published(false);
+        assert!(published.tagged.is_empty());
+
+        // Encoded on delivery instead
+        let message = tagged_message(&published.encode_tagged::<LineCodec>().unwrap());
+        assert_eq!(message.channel, "orders");
+        assert_eq!(published.encode::<LineCodec>().unwrap(), published.frame);
+    }
+
+    #[test]
+    fn acks_echo_ids() {
+        let mut batch = PublishBatch::new(BoundedBroadcast::unbounded(), 1024, Backpressure::Pause);
+
+        let ack = batch.push(Token(1), 1, message("a", "orders")).unwrap();
+        assert!(ack.is_ack());
+        assert_eq!(ack.id(), Some("a"));
+
+        let nack = batch.push(Token(1), 1, message("b", &"x".repeat(u16::MAX as usize + 1))).unwrap();
+        assert!(!nack.is_ack());
+        assert_eq!(nack.id(), Some("b"));
+
+        let confirm = Confirm { confirm: true, id: Some("c".into()) };
+        assert_eq!(batch.confirm(Token(1), confirm).unwrap().id(), Some("c"));
+    }
+
+    #[test]
+    fn confirm_acks_after_publish() {
+        let broadcast = BoundedBroadcast::bounded(1);
+        let (messages, lag, interest) = broadcast.subscriber().into_parts();
+        interest.add("orders");
+
+        let mut batch = PublishBatch::new(broadcast, 1024, Backpressure::Pause);
+        let confirm = Confirm { confirm: true, id: None };
+        assert!(batch.confirm(Token(1), confirm).unwrap().is_ack());
+
+        // Held until the batch is published
+        assert!(batch.push(Token(1), 1, message("a", "orders")).is_none());
+        let acks = batch.publish();
+        assert_eq!(acks.len(), 1);
+        assert_eq!((acks[0].0, acks[0].1.id()), (Token(1), Some("a")));
+
+        // The receiver has not caught up, so the next
+        // publish is held back and not acked when publishing
+        assert!(batch.push(Token(1), 1, message("b", "orders")).is_none());
+        assert!(batch.is_held(Token(1)));
+        assert!(batch.publish().is_empty());
+        assert!(batch.resume().is_empty());
+
+        let published = messages.try_recv().unwrap();
+        lag.received(published.len());
+        assert_eq!(batch.resume(), vec![Token(1)]);
+        let acks = batch.publish();
+        assert_eq!(acks.len(), 1);
+        assert_eq!(acks[0].1.id(), Some("b"));
+        assert!(messages.try_recv().is_ok());
+    }
+}
@@ -4,15 +4,24 @@ use std::thread;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::net::SocketAddr;
 
+use serde::Deserialize;
 use sonr::errors::Result;
 use sonr::net::tcp::{ReactiveTcpStream, TcpStream};
 use sonr::prelude::*;
 
 use pubsub::connection::Connection;
-use pubsub::messages::{Subscribe, PubMessage};
-use pubsub::codec::LineCodec;
+use pubsub::messages::{Subscribe, PubMessage, Ping, Pong};
+use pubsub::codec::{Codec, LineCodec};
 
 static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// The broker pings idle subscribers if heartbeats are on
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Received {
+    Message(PubMessage),
+    Ping(Ping),
+}
  
 struct Connections {
     connections: HashMap<Token, Connection>,
@@ -26,7 +35,7 @@ impl Connections {
             let stream = ReactiveTcpStream::new(stream).unwrap();
             let token = stream.token();
             let mut con = Connection::new(stream);
-            let payload = LineCodec::encode(Subscribe { channel: "abc".to_owned() }).unwrap();
+            let payload = LineCodec::encode(&Subscribe { channel: "abc".to_owned() }).unwrap();
             con.add_payload(payload);
             (token, con)
         }).collect::<HashMap<Token, Connection>>();
@@ -49,10 +58,15 @@ impl Reactor for Connections {
                 if let Some(connection) = self.connections.get_mut(&connection_id) {
                     connection.react(event.into());
 
-                    while let Some(messages) = connection.recv::<PubMessage>() {
+                    while let Some(messages) = connection.recv::<Received>() {
                         match messages {
                             Ok(msg) => {
-                                COUNTER.fetch_add(msg.len(), Ordering::SeqCst);
+                                let count = msg.iter().filter(|m| matches!(m, Ok(Received::Message(PubMessage { .. })))).count();
+                                COUNTER.fetch_add(count, Ordering::SeqCst);
+
+                                if msg.iter().any(|m| matches!(m, Ok(Received::Ping(ping)) if ping.ping)) {
+                                    connection.add_payload(LineCodec::encode(&Pong { pong: true }).unwrap());
+                                }
                             }
                             Err(_) => {
                                 self.connections.remove(&connection_id);
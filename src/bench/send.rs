@@ -5,17 +5,27 @@ use std::sync::atomic::{AtomicUsize, Ordering};
 use std::net::SocketAddr;
 use bytes::{Bytes, BytesMut, BufMut};
 
+use serde::Deserialize;
 use sonr::errors::Result;
 use sonr::net::tcp::{ReactiveTcpStream, TcpStream};
 use sonr::prelude::*;
 
 use pubsub::connection::Connection;
-use pubsub::messages::AckMessage;
+use pubsub::messages::{AckMessage, Ping, Pong};
+use pubsub::codec::{Codec, LineCodec};
 
 static COUNTER: AtomicUsize = AtomicUsize::new(0);
 static FAIL_COUNTER: AtomicUsize = AtomicUsize::new(0);
 const SEND_TRIGGER: usize = 256;
-static PAYLOAD: &'static [u8] = b"{\"channel\":\"abc\",\"payload\":\"hello\"}\n";
+static PAYLOAD: &[u8] = b"{\"channel\":\"abc\",\"payload\":\"aGVsbG8=\"}\n"; // "hello"
+
+// The broker pings idle publishers if heartbeats are on
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Received {
+    Ack(AckMessage),
+    Ping(Ping),
+}
 
 fn payload(count: usize) -> Bytes {
     let mut b = BytesMut::with_capacity(count * PAYLOAD.len());
@@ -61,11 +71,18 @@ impl Reactor for Connections {
                     connection.react(event.into());
 
                     let mut ok_msg_count = 0usize;
-                    while let Some(messages) = connection.recv::<AckMessage>() {
+                    while let Some(messages) = connection.recv::<Received>() {
                         match messages {
                             Ok(msg) => {
-                                COUNTER.fetch_add(msg.len(), Ordering::SeqCst);
-                                ok_msg_count += msg.len();
+                                let pings = msg.iter().filter(|m| matches!(m, Ok(Received::Ping(ping)) if ping.ping)).count();
+                                let count = msg.iter().filter(|m| matches!(m, Ok(Received::Ack(ack)) if ack.is_ack())).count();
+                                COUNTER.fetch_add(count, Ordering::SeqCst);
+                                FAIL_COUNTER.fetch_add(msg.len() - pings - count, Ordering::SeqCst);
+                                ok_msg_count += count;
+
+                                if pings > 0 {
+                                    connection.add_payload(LineCodec::encode(&Pong { pong: true }).unwrap());
+                                }
                             }
                             Err(_) => {
                                 self.connections.remove(&connection_id);
@@ -0,0 +1,402 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use sonr::sync::signal::{SignalReceiver, SignalSender};
+use bytes::{Bytes, BytesMut};
+
+use crate::batch::{put_message, Published};
+use crate::messages::{AckMessage, PubMessage};
+use crate::subscriptions::Interest;
+
+/// Broadcast of the published batches to every receiver (one per reactor),
+/// with an optional capacity.
+///
+/// Receivers only get the messages for channels they have subscribers for
+/// (see `Interest`), so a reactor is not kept busy with messages it would
+/// only throw away.
+///
+/// In-process subscribers (see `local::LocalClient`) get the messages
+/// themselves rather than the framed batches.
+///
+/// The capacity is how many bytes the slowest receiver may fall behind,
+/// once reached the broadcast is full and publishers should back off.
+#[derive(Clone)]
+pub struct BoundedBroadcast {
+    capacity: Option<u64>,
+    // Messages are also framed for the client port
+    tagged: bool,
+    // Receivers `capacity` bytes behind
+    lagging: Arc<AtomicUsize>,
+    receivers: Arc<Mutex<Vec<Receiver>>>,
+    locals: Arc<Mutex<Vec<LocalReceiver>>>,
+}
+
+/// How far a receiver is behind, shared by both ends.
+/// Each receiver keeps track of whether it is `capacity` bytes behind
+/// in the broadcast's count of lagging receivers, so checking for a full
+/// broadcast takes no lock.
+pub(crate) struct Lag {
+    sent: AtomicU64,
+    received: AtomicU64,
+    behind: AtomicBool,
+    capacity: Option<u64>,
+    lagging: Arc<AtomicUsize>,
+}
+
+impl Lag {
+    fn new(capacity: Option<u64>, lagging: Arc<AtomicUsize>) -> Self {
+        Self {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            behind: AtomicBool::new(false),
+            capacity,
+            lagging,
+        }
+    }
+
+    fn sent(&self, bytes: usize) {
+        self.sent.fetch_add(bytes as u64, Ordering::SeqCst);
+        self.update();
+    }
+
+    pub(crate) fn received(&self, bytes: usize) {
+        self.received.fetch_add(bytes as u64, Ordering::SeqCst);
+        self.update();
+    }
+
+    // Both ends update the counts concurrently, so flip `behind`
+    // until it agrees with the counts it was flipped for.
+    // Only the thread that flips it updates `lagging`.
+    fn update(&self) {
+        let capacity = match self.capacity {
+            Some(capacity) => capacity,
+            None => return,
+        };
+
+        loop {
+            let sent = self.sent.load(Ordering::SeqCst);
+            let behind = sent.saturating_sub(self.received.load(Ordering::SeqCst)) >= capacity;
+            if self.behind.compare_exchange(!behind, behind, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+                return;
+            }
+
+            if behind {
+                self.lagging.fetch_add(1, Ordering::SeqCst);
+            } else {
+                self.lagging.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+// A dropped receiver no longer holds back the publishers
+impl Drop for Lag {
+    fn drop(&mut self) {
+        if *self.behind.get_mut() {
+            self.lagging.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+// The sending end of a receiver
+struct Receiver {
+    sender: SignalSender<Bytes>,
+    interest: Interest,
+    lag: Weak<Lag>,
+}
+
+impl Receiver {
+    fn send(&mut self, messages: Bytes) {
+        if let Some(lag) = self.lag.upgrade() {
+            lag.sent(messages.len());
+        }
+        let _ = self.sender.send(messages);
+    }
+}
+
+// The sending end of an in-process subscription
+struct LocalReceiver {
+    sender: SignalSender<PubMessage>,
+    interest: Interest,
+    // Dropped along with the subscription
+    lag: Weak<Lag>,
+}
+
+impl LocalReceiver {
+    fn send(&self, message: PubMessage) {
+        if let Some(lag) = self.lag.upgrade() {
+            lag.sent(local_size(&message));
+        }
+        let _ = self.sender.send(message);
+    }
+}
+
+// What an in-process subscriber is sent counts towards its lag
+// like a framed message would, by channel and payload
+pub(crate) fn local_size(message: &PubMessage) -> usize {
+    message.channel.len() + message.payload.len()
+}
+
+impl BoundedBroadcast {
+    pub fn unbounded() -> Self {
+        Self::new(None)
+    }
+
+    pub fn bounded(capacity: usize) -> Self {
+        Self::new(Some(capacity))
+    }
+
+    fn new(capacity: Option<usize>) -> Self {
+        Self {
+            capacity: capacity.map(|capacity| capacity as u64),
+            tagged: false,
+            lagging: Arc::new(AtomicUsize::new(0)),
+            receivers: Arc::new(Mutex::new(Vec::new())),
+            locals: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Also frame the published messages for the client port,
+    /// rather than encoding them for every delivery.
+    /// Only worth it when there is a client listener.
+    pub fn with_tagged_frames(mut self) -> Self {
+        self.tagged = true;
+        self
+    }
+
+    pub(crate) fn tagged_frames(&self) -> bool {
+        self.tagged
+    }
+
+    /// A receiver for the messages published from here on,
+    /// on the channels subscribed to in its `Interest`
+    pub fn subscriber(&self) -> BroadcastReceiver {
+        let messages = SignalReceiver::unbounded();
+        let lag = Arc::new(Lag::new(self.capacity, self.lagging.clone()));
+        let interest = Interest::default();
+
+        if let Ok(mut receivers) = self.receivers.lock() {
+            receivers.push(Receiver {
+                sender: messages.sender(),
+                interest: interest.clone(),
+                lag: Arc::downgrade(&lag),
+            });
+        }
+
+        BroadcastReceiver {
+            messages,
+            lag,
+            interest,
+        }
+    }
+
+    /// Messages on the channels subscribed to in `interest`,
+    /// until the returned `Lag` is dropped.
+    /// The receiving end counts what it takes in the `Lag`.
+    pub(crate) fn local_subscriber(&self, interest: Interest) -> (SignalReceiver<PubMessage>, Arc<Lag>) {
+        let messages = SignalReceiver::unbounded();
+        let lag = Arc::new(Lag::new(self.capacity, self.lagging.clone()));
+        if let Ok(mut locals) = self.locals.lock() {
+            locals.push(LocalReceiver {
+                sender: messages.sender(),
+                interest,
+                lag: Arc::downgrade(&lag),
+            });
+        }
+        (messages, lag)
+    }
+
+    /// Send every receiver the messages of the batch it is interested in.
+    /// Consecutive messages are sent as a single slice of the batch.
+    pub fn publish(&self, batch: Bytes) {
+        // Find the message boundaries once, rather than for every receiver
+        let mut messages = Vec::new();
+        let mut rest = batch.clone();
+        while let Some(published) = Published::split(&mut rest) {
+            messages.push((batch.len() - rest.len(), published));
+        }
+
+        self.publish_local(&messages);
+
+        let mut receivers = match self.receivers.lock() {
+            Ok(receivers) => receivers,
+            Err(_) => return,
+        };
+
+        receivers.retain(|receiver| receiver.lag.strong_count() > 0);
+        for receiver in receivers.iter_mut() {
+            let mut start = 0;
+            let mut end = 0;
+            for (message_end, published) in &messages {
+                if receiver.interest.matches(published.channel()) {
+                    end = *message_end;
+                    continue;
+                }
+
+                if end > start {
+                    receiver.send(batch.slice(start, end));
+                }
+                start = *message_end;
+                end = *message_end;
+            }
+
+            if end > start {
+                receiver.send(batch.slice(start, end));
+            }
+        }
+    }
+
+    /// Publish a single message from an in-process client.
+    /// The message is only framed if a receiver is interested
+    /// in the channel, in-process subscribers get it as is.
+    pub(crate) fn publish_message(&self, message: PubMessage) -> Result<(), AckMessage> {
+        if let Ok(mut receivers) = self.receivers.lock() {
+            receivers.retain(|receiver| receiver.lag.strong_count() > 0);
+
+            let mut interested = receivers
+                .iter_mut()
+                .filter(|receiver| receiver.interest.matches(&message.channel))
+                .peekable();
+
+            if interested.peek().is_some() {
+                let mut payload = BytesMut::new();
+                put_message(&mut payload, &message, self.tagged)?;
+                let payload = payload.freeze();
+                interested.for_each(|receiver| receiver.send(payload.clone()));
+            }
+        }
+
+        if let Ok(mut locals) = self.locals.lock() {
+            locals.retain(|local| local.lag.strong_count() > 0);
+            locals
+                .iter()
+                .filter(|local| local.interest.matches(&message.channel))
+                .for_each(|local| local.send(message.clone()));
+        }
+
+        Ok(())
+    }
+
+    // Messages published over TCP are decoded once
+    // for all in-process subscribers
+    fn publish_local(&self, messages: &[(usize, Published)]) {
+        let mut locals = match self.locals.lock() {
+            Ok(locals) => locals,
+            Err(_) => return,
+        };
+
+        locals.retain(|local| local.lag.strong_count() > 0);
+        if locals.is_empty() {
+            return;
+        }
+
+        for (_, published) in messages {
+            let channel = published.channel();
+            if !locals.iter().any(|local| local.interest.matches(channel)) {
+                continue;
+            }
+
+            if let Some(message) = published.message() {
+                locals
+                    .iter()
+                    .filter(|local| local.interest.matches(channel))
+                    .for_each(|local| local.send(message.clone()));
+            }
+        }
+    }
+
+    /// The slowest receiver, in-process subscribers included,
+    /// is `capacity` bytes behind
+    pub fn is_full(&self) -> bool {
+        self.lagging.load(Ordering::SeqCst) > 0
+    }
+}
+
+/// Receiving end of a `BoundedBroadcast`
+pub struct BroadcastReceiver {
+    messages: SignalReceiver<Bytes>,
+    lag: Arc<Lag>,
+    interest: Interest,
+}
+
+impl BroadcastReceiver {
+    pub(crate) fn into_parts(self) -> (SignalReceiver<Bytes>, Arc<Lag>, Interest) {
+        (self.messages, self.lag, self.interest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lagging_receivers() {
+        let lagging = Arc::new(AtomicUsize::new(0));
+        let first = Lag::new(Some(10), lagging.clone());
+        let second = Lag::new(Some(10), lagging.clone());
+
+        first.sent(9);
+        assert_eq!(lagging.load(Ordering::SeqCst), 0);
+
+        first.sent(1);
+        second.sent(20);
+        assert_eq!(lagging.load(Ordering::SeqCst), 2);
+
+        // Catching up only counts once under the capacity
+        second.received(10);
+        assert_eq!(lagging.load(Ordering::SeqCst), 2);
+        second.received(1);
+        assert_eq!(lagging.load(Ordering::SeqCst), 1);
+
+        // A dropped receiver no longer counts
+        drop(first);
+        assert_eq!(lagging.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn unbounded_never_lags() {
+        let lagging = Arc::new(AtomicUsize::new(0));
+        let lag = Lag::new(None, lagging.clone());
+        lag.sent(usize::MAX);
+        assert_eq!(lagging.load(Ordering::SeqCst), 0);
+    }
+
+    fn batch(channels: &[&str]) -> Bytes {
+        let mut payload = BytesMut::new();
+        for channel in channels {
+            put_message(&mut payload, &PubMessage::new(*channel, "hi"), false).unwrap();
+        }
+        payload.freeze()
+    }
+
+    fn channels(mut batch: Bytes) -> Vec<String> {
+        let mut channels = Vec::new();
+        while let Some(published) = Published::split(&mut batch) {
+            channels.push(published.channel().to_string());
+        }
+        assert!(batch.is_empty());
+        channels
+    }
+
+    #[test]
+    fn publish_slices_per_receiver() {
+        let broadcast = BoundedBroadcast::unbounded();
+        let (orders, _orders_lag, orders_interest) = broadcast.subscriber().into_parts();
+        let (users, _users_lag, users_interest) = broadcast.subscriber().into_parts();
+        let (none, _none_lag, _) = broadcast.subscriber().into_parts();
+        orders_interest.add("orders.*");
+        users_interest.add("users");
+
+        broadcast.publish(batch(&["orders.eu", "orders.us", "users", "orders.eu"]));
+
+        // Consecutive matches are sent as one slice
+        assert_eq!(channels(orders.try_recv().unwrap()), vec!["orders.eu", "orders.us"]);
+        assert_eq!(channels(orders.try_recv().unwrap()), vec!["orders.eu"]);
+        assert!(orders.try_recv().is_err());
+
+        assert_eq!(channels(users.try_recv().unwrap()), vec!["users"]);
+        assert!(users.try_recv().is_err());
+
+        assert!(none.try_recv().is_err());
+    }
+}
@@ -0,0 +1,551 @@
+use std::io;
+use std::net::{self, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sonr::prelude::*;
+use sonr::errors::Result;
+use sonr::net::tcp::{ReactiveTcpListener, TcpListener, TcpStream};
+use sonr::sync::queue::{ReactiveDeque, ReactiveQueue};
+
+use crate::broadcast::BoundedBroadcast;
+use crate::codec::{BinaryCodec, Format, LineCodec};
+#[cfg(feature = "cbor")]
+use crate::codec::CborCodec;
+#[cfg(feature = "msgpack")]
+use crate::codec::MsgPackCodec;
+use crate::config::Config;
+use crate::local::LocalClient;
+use crate::options::Options;
+use crate::peer::Peer;
+use crate::publisher::Publisher;
+use crate::shutdown::{DrainGroup, Shutdown, Stop};
+use crate::subscriber::Subscriber;
+use crate::timer::Timer;
+
+// Heartbeats are checked this often, see `Options::heartbeat_interval`
+const HEARTBEAT_TICK: Duration = Duration::from_secs(1);
+
+/// Configures the listeners, threads and options of a `Broker`.
+///
+/// Listeners are only started for the ports given an address.
+/// Use port 0 to bind to any free port, the bound addresses
+/// are available on the `Broker` once built.
+pub struct BrokerBuilder {
+    publisher: Option<String>,
+    subscriber: Option<String>,
+    client: Option<String>,
+    formats: Formats,
+    threads: usize,
+    buffer_threshold: usize,
+    publish_timeout: Duration,
+    broadcast_capacity: Option<usize>,
+    drain_timeout: Duration,
+    publisher_options: Options,
+    subscriber_options: Options,
+    client_options: Options,
+}
+
+// The codec of each port
+#[derive(Clone, Copy, Default)]
+struct Formats {
+    publisher: Format,
+    subscriber: Format,
+    client: Format,
+}
+
+impl BrokerBuilder {
+    /// Address of the publisher port
+    pub fn publisher(mut self, addr: impl Into<String>) -> Self {
+        self.publisher = Some(addr.into());
+        self
+    }
+
+    /// Address of the subscriber port
+    pub fn subscriber(mut self, addr: impl Into<String>) -> Self {
+        self.subscriber = Some(addr.into());
+        self
+    }
+
+    /// Address of the client port
+    /// (publish and subscribe on the same connection)
+    pub fn client(mut self, addr: impl Into<String>) -> Self {
+        self.client = Some(addr.into());
+        self
+    }
+
+    /// Codec of the publisher port, newline delimited JSON by default
+    pub fn publisher_codec(mut self, format: Format) -> Self {
+        self.formats.publisher = format;
+        self
+    }
+
+    /// Codec of the subscriber port, newline delimited JSON by default
+    pub fn subscriber_codec(mut self, format: Format) -> Self {
+        self.formats.subscriber = format;
+        self
+    }
+
+    /// Codec of the client port, newline delimited JSON by default
+    pub fn client_codec(mut self, format: Format) -> Self {
+        self.formats.client = format;
+        self
+    }
+
+    /// Number of threads handling the connections
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Publish a batch once this many bytes are buffered
+    pub fn buffer_threshold(mut self, buffer_threshold: usize) -> Self {
+        self.buffer_threshold = buffer_threshold;
+        self
+    }
+
+    /// Publish whatever is buffered this often
+    pub fn publish_timeout(mut self, publish_timeout: Duration) -> Self {
+        self.publish_timeout = publish_timeout;
+        self
+    }
+
+    /// See `BoundedBroadcast::bounded`. `None` is unbounded.
+    pub fn broadcast_capacity(mut self, capacity: Option<usize>) -> Self {
+        self.broadcast_capacity = capacity;
+        self
+    }
+
+    /// How long connections are given to receive what is
+    /// queued for them when the broker shuts down
+    pub fn drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Options for the publisher and subscriber ports
+    pub fn options(mut self, options: Options) -> Self {
+        self.publisher_options = options.clone();
+        self.subscriber_options = options;
+        self
+    }
+
+    /// Options for the publisher port
+    pub fn publisher_options(mut self, options: Options) -> Self {
+        self.publisher_options = options;
+        self
+    }
+
+    /// Options for the subscriber port
+    pub fn subscriber_options(mut self, options: Options) -> Self {
+        self.subscriber_options = options;
+        self
+    }
+
+    /// Options for the client port
+    pub fn client_options(mut self, options: Options) -> Self {
+        self.client_options = options;
+        self
+    }
+
+    /// Use the listeners, threads and options of a config
+    pub fn config(self, config: &Config) -> Self {
+        let mut builder = self
+            .threads(config.threads)
+            .buffer_threshold(config.batch.threshold)
+            .publish_timeout(config.publish_timeout())
+            .broadcast_capacity(config.limits.broadcast_capacity)
+            .drain_timeout(config.drain_timeout())
+            .publisher_options(config.publisher_options())
+            .subscriber_options(config.subscriber_options())
+            .client_options(config.client_options())
+            .publisher_codec(config.listeners.publisher_codec)
+            .subscriber_codec(config.listeners.subscriber_codec)
+            .client_codec(config.listeners.client_codec);
+
+        let listeners = &config.listeners;
+        builder.publisher = Some(listeners.publisher.clone()).filter(|_| config.features.publisher);
+        builder.subscriber = Some(listeners.subscriber.clone()).filter(|_| config.features.subscriber);
+        builder.client = Some(listeners.client.clone()).filter(|_| config.features.client);
+        builder
+    }
+
+    /// Bind the listeners.
+    /// Nothing is accepted until the broker is run.
+    pub fn build(self) -> Result<Broker> {
+        if self.publisher.is_none() && self.subscriber.is_none() && self.client.is_none() {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "no listeners configured");
+            return Err(err.into());
+        }
+
+        if self.threads == 0 {
+            let err = io::Error::new(io::ErrorKind::InvalidInput, "at least one thread is required");
+            return Err(err.into());
+        }
+
+        let mut broadcast = match self.broadcast_capacity {
+            Some(capacity) => BoundedBroadcast::bounded(capacity),
+            None => BoundedBroadcast::unbounded(),
+        };
+
+        // Messages are only framed for the client port if there is one
+        let client = bind(self.client.as_ref())?;
+        if client.is_some() {
+            broadcast = broadcast.with_tagged_frames();
+        }
+
+        Ok(Broker {
+            publisher: bind(self.publisher.as_ref())?,
+            subscriber: bind(self.subscriber.as_ref())?,
+            client,
+            formats: self.formats,
+            threads: self.threads,
+            buffer_threshold: self.buffer_threshold,
+            publish_timeout: self.publish_timeout,
+            broadcast,
+            publisher_options: self.publisher_options,
+            subscriber_options: self.subscriber_options,
+            client_options: self.client_options,
+            handle: BrokerHandle {
+                shutdown: Shutdown::default(),
+                drain_timeout: self.drain_timeout,
+            },
+        })
+    }
+}
+
+fn bind(addr: Option<&String>) -> Result<Option<net::TcpListener>> {
+    match addr {
+        Some(addr) => Ok(Some(net::TcpListener::bind(addr.as_str())?)),
+        None => Ok(None),
+    }
+}
+
+// Build a port's reactor with the codec of its `Format`,
+// binding the codec type to `$codec` in `$build`
+macro_rules! with_codec {
+    ($format:expr, |$codec:ident| $build:expr) => {
+        match $format {
+            Format::Line => {
+                type $codec = LineCodec;
+                Port::new($build)
+            }
+            Format::Binary => {
+                type $codec = BinaryCodec;
+                Port::new($build)
+            }
+            #[cfg(feature = "msgpack")]
+            Format::MsgPack => {
+                type $codec = MsgPackCodec;
+                Port::new($build)
+            }
+            #[cfg(feature = "cbor")]
+            Format::Cbor => {
+                type $codec = CborCodec;
+                Port::new($build)
+            }
+        }
+    };
+}
+// A port's reactor, whatever its codec
+struct Port {
+    reactor: Box<dyn FnMut(Reaction<()>) -> Reaction<()>>,
+}
+
+impl Port {
+    fn new<R: Reactor<Input = (), Output = ()> + 'static>(mut reactor: R) -> Self {
+        Self {
+            reactor: Box::new(move |reaction| reactor.react(reaction)),
+        }
+    }
+}
+
+impl Reactor for Port {
+    type Input = ();
+    type Output = ();
+
+    fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
+        (self.reactor)(reaction)
+    }
+}
+
+/// The publisher, subscriber and client ports, and the
+/// threads handling their connections.
+///
+/// ```no_run
+/// # fn main() -> sonr::errors::Result<()> {
+/// let broker = pubsub::Broker::builder()
+///     .publisher("127.0.0.1:0")
+///     .subscriber("127.0.0.1:0")
+///     .build()?;
+///
+/// let handle = broker.handle();
+/// println!("publish on {:?}", broker.publisher_addr());
+/// std::thread::spawn(move || broker.run());
+/// // ...
+/// handle.shutdown();
+/// # Ok(())
+/// # }
+/// ```
+pub struct Broker {
+    publisher: Option<net::TcpListener>,
+    subscriber: Option<net::TcpListener>,
+    client: Option<net::TcpListener>,
+    formats: Formats,
+    threads: usize,
+    buffer_threshold: usize,
+    publish_timeout: Duration,
+    broadcast: BoundedBroadcast,
+    publisher_options: Options,
+    subscriber_options: Options,
+    client_options: Options,
+    handle: BrokerHandle,
+}
+
+impl Broker {
+    pub fn builder() -> BrokerBuilder {
+        BrokerBuilder {
+            publisher: None,
+            subscriber: None,
+            client: None,
+            formats: Formats::default(),
+            threads: 8,
+            buffer_threshold: 256,
+            publish_timeout: Duration::from_millis(20),
+            broadcast_capacity: None,
+            drain_timeout: Duration::from_secs(5),
+            publisher_options: Options::default(),
+            subscriber_options: Options::default(),
+            client_options: Options::default(),
+        }
+    }
+
+    pub fn publisher_addr(&self) -> Option<SocketAddr> {
+        self.publisher.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    pub fn subscriber_addr(&self) -> Option<SocketAddr> {
+        self.subscriber.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    pub fn client_addr(&self) -> Option<SocketAddr> {
+        self.client.as_ref().and_then(|l| l.local_addr().ok())
+    }
+
+    /// Publish and subscribe from within the process.
+    /// Clients can be created before the broker is run.
+    pub fn local_client(&self) -> LocalClient {
+        LocalClient::new(self.broadcast.clone())
+    }
+
+    /// A handle to shut the broker down from another thread
+    pub fn handle(&self) -> BrokerHandle {
+        self.handle.clone()
+    }
+
+    /// Shut the broker down, see `BrokerHandle::shutdown`
+    pub fn shutdown(&self) {
+        self.handle.shutdown();
+    }
+
+    /// Start the worker threads and accept connections
+    /// on the current thread until the broker is shut down.
+    /// Returns the first error of the listeners or the threads,
+    /// a thread that panicked is an error too.
+    pub fn run(self) -> Result<()> {
+        System::init()?;
+
+        let broadcast = self.broadcast;
+        let mut timer = Timer::new(self.publish_timeout);
+        let mut heartbeat_timer = Timer::new(HEARTBEAT_TICK);
+        // The timers keep ticking while the connections are drained
+        let timer_stopped = timer.stop_flag();
+        let heartbeat_stopped = heartbeat_timer.stop_flag();
+
+        let mut pub_connection_queue = ReactiveQueue::unbounded();
+        let mut sub_connection_queue = ReactiveQueue::unbounded();
+        let mut client_connection_queue = ReactiveQueue::unbounded();
+
+        // Every thread reports whether it started before the listeners do
+        let (started_tx, started_rx) = mpsc::channel();
+
+        let mut workers = Vec::with_capacity(self.threads);
+        for _ in 0..self.threads {
+            let broadcast = broadcast.clone();
+            let buffer_threshold = self.buffer_threshold;
+            let publisher_options = self.publisher_options.clone();
+            let subscriber_options = self.subscriber_options.clone();
+            let client_options = self.client_options.clone();
+            let timer_notifier = timer.receiver();
+            let client_timer_notifier = timer.receiver();
+            let pub_heartbeat = heartbeat_timer.receiver();
+            let sub_heartbeat = heartbeat_timer.receiver();
+            let client_heartbeat = heartbeat_timer.receiver();
+            let pub_deque = pub_connection_queue.deque();
+            let sub_deque = sub_connection_queue.deque();
+            let client_deque = client_connection_queue.deque();
+            let shutdown = self.handle.shutdown.clone();
+            let formats = self.formats;
+            let started = started_tx.clone();
+
+            workers.push(thread::spawn(move || -> Result<()> {
+                let run = (|| -> Result<_> {
+                    System::init()?;
+
+                    // The thread stops once all three have drained
+                    let drain = DrainGroup::default();
+
+                    let sub_run = with_codec!(formats.subscriber, |C| {
+                        let sub_connection_deque = ReactiveDeque::new(sub_deque)?;
+                        let subscriber = Subscriber::<C>::new(broadcast.subscriber(), sub_heartbeat, drain.drain(&shutdown)?, subscriber_options)?;
+                        sub_connection_deque.chain(subscriber)
+                    });
+
+                    let pub_run = with_codec!(formats.publisher, |C| {
+                        let pub_connection_deque = ReactiveDeque::new(pub_deque)?;
+                        let publisher = Publisher::<C>::new(broadcast.clone(), buffer_threshold, timer_notifier, pub_heartbeat, drain.publisher_drain(&shutdown)?, publisher_options)?;
+                        pub_connection_deque.chain(publisher)
+                    });
+
+                    let client_run = with_codec!(formats.client, |C| {
+                        let client_connection_deque = ReactiveDeque::new(client_deque)?;
+                        let peer = Peer::<C>::new(broadcast, buffer_threshold, client_timer_notifier, client_heartbeat, drain.publisher_drain(&shutdown)?, client_options)?;
+                        client_connection_deque.chain(peer)
+                    });
+
+                    Ok(pub_run.and(sub_run).and(client_run))
+                })();
+
+                let _ = started.send(run.is_ok());
+                System::start(run?)?;
+
+                Ok(())
+            }));
+        }
+        drop(started_tx);
+
+        timer.start();
+        heartbeat_timer.start();
+
+        let (publisher, subscriber, client) = (self.publisher, self.subscriber, self.client);
+        let shutdown = &self.handle.shutdown;
+        let accept = || -> Result<()> {
+            let pub_run = Listener::new(publisher)?.chain(pub_connection_queue);
+            let sub_run = Listener::new(subscriber)?.chain(sub_connection_queue);
+            let client_run = Listener::new(client)?.chain(client_connection_queue);
+            // Stop accepting connections right away
+            let stop = Stop::new(DrainGroup::default().drain(shutdown)?);
+
+            System::start(pub_run.and(sub_run).and(client_run).and(stop))
+        };
+
+        // A thread that failed to start returns its error when joined
+        let started = (0..self.threads).all(|_| started_rx.recv().unwrap_or(false));
+        let mut result = if started { accept() } else { Ok(()) };
+        if !started || result.is_err() {
+            // Drain the threads that did start
+            self.handle.shutdown();
+        }
+
+        for worker in workers {
+            let res = worker.join().unwrap_or_else(|_| Err(io::Error::other("worker thread panicked").into()));
+            result = result.and(res);
+        }
+
+        timer_stopped.store(true, Ordering::SeqCst);
+        heartbeat_stopped.store(true, Ordering::SeqCst);
+
+        result
+    }
+}
+
+/// Shuts down a running `Broker`
+#[derive(Clone)]
+pub struct BrokerHandle {
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+}
+
+impl BrokerHandle {
+    /// Stop accepting connections, publish the buffered messages and
+    /// send every connection a goodbye. Connections are closed once
+    /// everything queued for them is written, or the drain timeout
+    /// has passed. `Broker::run` returns once all threads have stopped.
+    pub fn shutdown(&self) {
+        self.shutdown.shutdown(Instant::now() + self.drain_timeout);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shutdown.is_shutdown()
+    }
+}
+
+// A listener for a port that may not be configured
+enum Listener {
+    Enabled(ReactiveTcpListener),
+    Disabled,
+}
+
+impl Listener {
+    fn new(listener: Option<net::TcpListener>) -> Result<Self> {
+        match listener {
+            Some(listener) => {
+                let listener = TcpListener::from_std(listener)?;
+                Ok(Listener::Enabled(ReactiveTcpListener::new(listener)?))
+            }
+            None => Ok(Listener::Disabled),
+        }
+    }
+}
+
+impl Reactor for Listener {
+    type Input = ();
+    type Output = TcpStream;
+
+    fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
+        match self {
+            Listener::Enabled(listener) => match listener.react(reaction) {
+                Reaction::Value((stream, _)) => Reaction::Value(stream),
+                Reaction::Event(event) => Reaction::Event(event),
+                Reaction::Continue => Reaction::Continue,
+            }
+            Listener::Disabled => match reaction {
+                Reaction::Event(event) => Reaction::Event(event),
+                _ => Reaction::Continue,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn binds_free_ports() {
+        let broker = Broker::builder()
+            .publisher("127.0.0.1:0")
+            .subscriber("127.0.0.1:0")
+            .build()
+            .unwrap();
+
+        let publisher = broker.publisher_addr().unwrap();
+        let subscriber = broker.subscriber_addr().unwrap();
+        assert_ne!(publisher.port(), 0);
+        assert_ne!(subscriber.port(), 0);
+        assert_ne!(publisher, subscriber);
+        assert_eq!(broker.client_addr(), None);
+    }
+
+    #[test]
+    fn bind_errors() {
+        let broker = Broker::builder().publisher("127.0.0.1:0").build().unwrap();
+        let taken = broker.publisher_addr().unwrap().to_string();
+
+        assert!(Broker::builder().subscriber(taken).build().is_err());
+        assert!(Broker::builder().build().is_err());
+        assert!(Broker::builder().publisher("127.0.0.1:0").threads(0).build().is_err());
+    }
+}
@@ -0,0 +1,454 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::codec::LineCodec;
+use crate::connection::Connection;
+use crate::messages::{
+    ClientMessage, ErrorMessage, Hello, PubMessage, ServerMessage, Subscribe, PROTOCOL_VERSIONS,
+};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Io(io::Error),
+    /// Nothing was received within the timeout
+    Timeout,
+    /// The connection was closed (or the broker said goodbye)
+    Closed,
+    /// The broker could not decode a frame, or sent one we could not decode
+    InvalidFrame(String),
+    /// The publish was nacked
+    Nack(Option<String>),
+    /// The broker replied with an error
+    Server(ErrorMessage),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Io(e) => write!(f, "{}", e),
+            ClientError::Timeout => write!(f, "timed out"),
+            ClientError::Closed => write!(f, "connection closed"),
+            ClientError::InvalidFrame(reason) => write!(f, "invalid frame: {}", reason),
+            ClientError::Nack(reason) => write!(f, "nacked: {}", reason.as_deref().unwrap_or("no reason")),
+            ClientError::Server(err) => write!(f, "{:?}: {}", err.code, err.reason),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<io::Error> for ClientError {
+    fn from(err: io::Error) -> Self {
+        ClientError::Io(err)
+    }
+}
+
+/// A blocking client for the client port,
+/// publishing and subscribing on a single connection.
+///
+/// Messages received while waiting for a reply (such as an ack)
+/// are kept until read with `next_message` or `messages`.
+///
+/// ```no_run
+/// # use pubsub::client::PubSubClient;
+/// # use pubsub::messages::PubMessage;
+/// # fn main() -> Result<(), pubsub::client::ClientError> {
+/// let mut client = PubSubClient::connect("127.0.0.1:7000")?;
+/// client.subscribe("orders.>")?;
+/// client.publish(PubMessage::new("orders.created", "hello"))?;
+///
+/// for message in client.messages() {
+///     let message = message?;
+///     println!("{}: {:?}", message.channel, message.payload);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct PubSubClient {
+    connection: Connection<LineCodec, TcpStream>,
+    // Used to set the read timeout, the connection owns the stream
+    stream: TcpStream,
+    timeout: Option<Duration>,
+    received: VecDeque<ServerMessage>,
+    messages: VecDeque<PubMessage>,
+    next_id: u64,
+    closed: bool,
+}
+
+impl PubSubClient {
+    /// Connect and agree on a protocol version with the broker
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self, ClientError> {
+        Self::connect_timeout(addr, None)
+    }
+
+    /// Connect, waiting no longer than `timeout` for the connection
+    /// (to each address `addr` resolves to) and then for any reply
+    pub fn connect_timeout<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> Result<Self, ClientError> {
+        let stream = connect(addr, timeout)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(timeout)?;
+
+        let mut client = Self {
+            connection: Connection::new(stream.try_clone()?),
+            stream,
+            timeout,
+            received: VecDeque::new(),
+            messages: VecDeque::new(),
+            next_id: 1,
+            closed: false,
+        };
+
+        client.send(&ClientMessage::Hello(Hello::new(PROTOCOL_VERSIONS.to_vec())))?;
+        loop {
+            match client.reply()? {
+                ServerMessage::Hello(_) => return Ok(client),
+                ServerMessage::Error(err) => return Err(ClientError::Server(err)),
+                _ => {}
+            }
+        }
+    }
+
+    /// How long to wait for a reply or a message.
+    /// `None` waits forever.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) -> Result<(), ClientError> {
+        self.stream.set_write_timeout(timeout)?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    /// Publish a message and wait for the ack.
+    /// Messages without an id are given one, to match the ack.
+    pub fn publish(&mut self, mut message: PubMessage) -> Result<(), ClientError> {
+        let id = match message.id.clone() {
+            Some(id) => id,
+            None => {
+                let id = self.next_id.to_string();
+                self.next_id += 1;
+                message.id = Some(id.clone());
+                id
+            }
+        };
+
+        self.send(&ClientMessage::Publish(message))?;
+        loop {
+            match self.reply()? {
+                // Acks for earlier publishes that timed out are skipped
+                ServerMessage::Ack(ack) if ack.id() == Some(id.as_str()) => {
+                    if ack.is_ack() {
+                        return Ok(());
+                    }
+                    return Err(ClientError::Nack(ack.reason().map(String::from)));
+                }
+                ServerMessage::Error(err) => return Err(ClientError::Server(err)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Subscribe to a channel pattern and wait until subscribed
+    pub fn subscribe(&mut self, pattern: &str) -> Result<(), ClientError> {
+        self.send(&ClientMessage::Subscribe(Subscribe { channel: pattern.to_string() }))?;
+        loop {
+            match self.reply()? {
+                ServerMessage::Subscribed(_) => return Ok(()),
+                ServerMessage::Error(err) => return Err(ClientError::Server(err)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Unsubscribe from a channel pattern.
+    /// Messages already on their way are still received.
+    pub fn unsubscribe(&mut self, pattern: &str) -> Result<(), ClientError> {
+        self.send(&ClientMessage::Unsubscribe { channel: pattern.to_string() })?;
+        loop {
+            match self.reply()? {
+                ServerMessage::Unsubscribed(_) => return Ok(()),
+                ServerMessage::Error(err) => return Err(ClientError::Server(err)),
+                _ => {}
+            }
+        }
+    }
+
+    /// Wait for the next message on the subscribed channels
+    pub fn next_message(&mut self) -> Result<PubMessage, ClientError> {
+        let deadline = self.deadline();
+        loop {
+            if let Some(message) = self.messages.pop_front() {
+                return Ok(message);
+            }
+
+            // Anything else is a late reply, or a notice
+            // such as a slow consumer, which is skipped
+            if let Some(ServerMessage::Error(err)) = self.recv(deadline, true)? {
+                return Err(ClientError::Server(err));
+            }
+        }
+    }
+
+    /// The messages on the subscribed channels,
+    /// until the connection is closed
+    pub fn messages(&mut self) -> Messages<'_> {
+        Messages { client: self }
+    }
+
+    // Wait for anything other than a message, which is kept for later
+    fn reply(&mut self) -> Result<ServerMessage, ClientError> {
+        let deadline = self.deadline();
+        loop {
+            if let Some(reply) = self.recv(deadline, false)? {
+                return Ok(reply);
+            }
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    // The next message from the broker that is not a delivery.
+    // Deliveries are queued and pings answered along the way.
+    // With `deliveries` it returns `None` once a delivery is queued.
+    fn recv(&mut self, deadline: Option<Instant>, deliveries: bool) -> Result<Option<ServerMessage>, ClientError> {
+        loop {
+            while let Some(message) = self.received.pop_front() {
+                match message {
+                    ServerMessage::Message(message) => self.messages.push_back(message),
+                    ServerMessage::Ping => self.send(&ClientMessage::Pong)?,
+                    ServerMessage::Goodbye => self.closed = true,
+                    message => return Ok(Some(message)),
+                }
+            }
+
+            if deliveries && !self.messages.is_empty() {
+                return Ok(None);
+            }
+
+            // Deliveries queued before the goodbye are still returned
+            if self.closed {
+                return Err(ClientError::Closed);
+            }
+
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(ClientError::Timeout);
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            self.stream.set_read_timeout(timeout)?;
+
+            match self.connection.recv::<ServerMessage>() {
+                // Timed out, checked above
+                None => {}
+                Some(Err(())) => {
+                    self.closed = true;
+                    return Err(ClientError::Closed);
+                }
+                // The frames after a malformed one are still queued
+                Some(Ok(frames)) => {
+                    let mut invalid = None;
+                    for frame in frames {
+                        match frame {
+                            Ok(message) => self.received.push_back(message),
+                            Err(e) => { invalid.get_or_insert(e); }
+                        }
+                    }
+
+                    if let Some(e) = invalid {
+                        return Err(ClientError::InvalidFrame(e.to_string()));
+                    }
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, message: &ClientMessage) -> Result<(), ClientError> {
+        if self.closed {
+            return Err(ClientError::Closed);
+        }
+
+        self.connection
+            .send(message)
+            .map_err(|e| ClientError::InvalidFrame(e.to_string()))?;
+
+        while !self.connection.is_flushed() {
+            match self.connection.write() {
+                Some(Ok(_)) => {}
+                Some(Err(())) => {
+                    self.closed = true;
+                    return Err(ClientError::Closed);
+                }
+                // The write timed out
+                None => return Err(ClientError::Timeout),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Iterator over the messages of a `PubSubClient`, see `PubSubClient::messages`.
+/// Ends once the connection is closed, timeouts are returned as errors.
+pub struct Messages<'a> {
+    client: &'a mut PubSubClient,
+}
+
+impl<'a> Iterator for Messages<'a> {
+    type Item = Result<PubMessage, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.next_message() {
+            Err(ClientError::Closed) => None,
+            res => Some(res),
+        }
+    }
+}
+
+// Like `TcpStream::connect`, trying each address in turn,
+// but bounding every attempt by the timeout
+fn connect<A: ToSocketAddrs>(addr: A, timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return TcpStream::connect(addr),
+    };
+
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any addresses")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use crate::messages::{AckMessage, SubscribeAck};
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    // A broker answering the hello, and every other message with `replies`.
+    // The messages received are sent on the returned channel.
+    fn broker<F>(mut replies: F) -> (SocketAddr, mpsc::Receiver<ClientMessage>)
+    where
+        F: FnMut(&ClientMessage) -> Vec<ServerMessage> + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received, messages) = mpsc::channel();
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            for line in BufReader::new(stream).lines() {
+                let message = serde_json::from_str::<ClientMessage>(&line.unwrap()).unwrap();
+                let replies = match &message {
+                    ClientMessage::Hello(hello) => vec![ServerMessage::Hello(Hello::new(vec![hello.negotiate().unwrap()]))],
+                    message => replies(message),
+                };
+
+                for reply in replies {
+                    writeln!(writer, "{}", serde_json::to_string(&reply).unwrap()).unwrap();
+                }
+                let _ = received.send(message);
+            }
+        });
+
+        (addr, messages)
+    }
+
+    #[test]
+    fn connect_timeout() {
+        // Accepted by the OS, but never answered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        match PubSubClient::connect_timeout(addr, Some(Duration::from_millis(50))) {
+            Err(ClientError::Timeout) => {}
+            res => panic!("expected a timeout, got {:?}", res.err()),
+        }
+    }
+
+    #[test]
+    fn publish_waits_for_the_ack() {
+        let (addr, received) = broker(|message| match message {
+            ClientMessage::Publish(message) if message.channel == "full" => {
+                vec![AckMessage::nack(message.id.clone(), "broker overloaded").into()]
+            }
+            ClientMessage::Publish(message) => vec![
+                // A late ack and a delivery on the way are skipped
+                AckMessage::ack(Some("late".into())).into(),
+                ServerMessage::Message(PubMessage::new("orders", "delivered")),
+                AckMessage::ack(message.id.clone()).into(),
+            ],
+            _ => Vec::new(),
+        });
+
+        let mut client = PubSubClient::connect_timeout(addr, TIMEOUT).unwrap();
+        assert!(matches!(received.recv().unwrap(), ClientMessage::Hello(_)));
+
+        client.publish(PubMessage::new("orders", "hi")).unwrap();
+        match received.recv().unwrap() {
+            ClientMessage::Publish(message) => assert_eq!(message.id.as_deref(), Some("1")),
+            message => panic!("unexpected message: {:?}", message),
+        }
+
+        match client.publish(PubMessage::new("full", "hi")) {
+            Err(ClientError::Nack(reason)) => assert_eq!(reason.as_deref(), Some("broker overloaded")),
+            res => panic!("expected a nack, got {:?}", res),
+        }
+
+        // The delivery received while waiting is kept
+        assert_eq!(&client.next_message().unwrap().payload[..], b"delivered");
+    }
+
+    #[test]
+    fn subscribe_and_receive() {
+        let (addr, received) = broker(|message| match message {
+            ClientMessage::Subscribe(subscribe) => vec![
+                ServerMessage::Subscribed(SubscribeAck::new(subscribe.channel.clone())),
+                ServerMessage::Ping,
+                ServerMessage::Message(PubMessage::new("orders.eu", "first")),
+                ServerMessage::Message(PubMessage::new("orders.us", "second")),
+                ServerMessage::Goodbye,
+            ],
+            _ => Vec::new(),
+        });
+
+        let mut client = PubSubClient::connect_timeout(addr, TIMEOUT).unwrap();
+        client.subscribe("orders.*").unwrap();
+
+        let channels = client
+            .messages()
+            .map(|message| message.unwrap().channel)
+            .collect::<Vec<_>>();
+        assert_eq!(channels, vec!["orders.eu", "orders.us"]);
+
+        assert!(matches!(client.publish(PubMessage::new("orders", "hi")), Err(ClientError::Closed)));
+
+        // The ping was answered
+        drop(client);
+        assert!(received.iter().any(|message| matches!(message, ClientMessage::Pong)));
+    }
+
+    #[test]
+    fn display_nack() {
+        assert_eq!(ClientError::Nack(Some("full".into())).to_string(), "nacked: full");
+        assert_eq!(ClientError::Nack(None).to_string(), "nacked: no reason");
+    }
+}
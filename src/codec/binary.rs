@@ -0,0 +1,549 @@
+use std::fmt;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::ser::{self, Impossible, Serialize, SerializeStruct, Serializer};
+use serde::{forward_to_deserialize_any, Deserializer};
+use serde_json::{Map, Value};
+
+use super::{Codec, Decoded, DecodeError, EncodeError};
+use crate::BUFFER_SIZE;
+
+// Frame layout:
+//
+// +------+-------------+----------+-------------+---------+------+---------+
+// | type | channel len | meta len | payload len | channel | meta | payload |
+// |  u8  |   u16 (BE)  | u16 (BE) |   u32 (BE)  |         |      |         |
+// +------+-------------+----------+-------------+---------+------+---------+
+const HEADER_LENGTH: usize = 9;
+
+/// A message with a channel and a payload of raw bytes.
+/// Any other fields of the message (content type etc.)
+/// are sent as a JSON object in the meta section.
+const MESSAGE: u8 = 1;
+
+/// Any other message (subscribe, acks, errors etc.).
+/// The channel is empty and the payload is JSON.
+const CONTROL: u8 = 2;
+
+/// Length prefixed binary frames.
+/// Messages with a `channel` and a `payload` are sent as `MESSAGE` frames,
+/// so the payload is never escaped.
+pub struct BinaryCodec {
+    max_frame_length: usize,
+}
+
+impl Codec for BinaryCodec {
+    fn new(max_frame_length: usize) -> Self {
+        Self { max_frame_length }
+    }
+
+    fn decode<T: DeserializeOwned>(&mut self, buf: &mut BytesMut) -> Decoded<T> {
+        if buf.len() < HEADER_LENGTH {
+            return Decoded::Incomplete;
+        }
+
+        let frame_type = buf[0];
+        let channel_length = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+        let meta_length = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+        let payload_length = u32::from_be_bytes([buf[5], buf[6], buf[7], buf[8]]) as usize;
+        let frame_length = HEADER_LENGTH + channel_length + meta_length + payload_length;
+
+        // The header tells us up front, so there is
+        // no need to wait for the rest of the frame.
+        if frame_length > self.max_frame_length {
+            buf.clear();
+            return Decoded::Malformed(DecodeError::FrameTooLong(self.max_frame_length));
+        }
+
+        if buf.len() < frame_length {
+            buf.reserve(frame_length - buf.len());
+            return Decoded::Incomplete;
+        }
+
+        let frame = buf.split_to(frame_length).freeze();
+        buf.reserve(BUFFER_SIZE); // Make sure the buffer can hold more data
+
+        let meta_start = HEADER_LENGTH + channel_length;
+        let payload_start = meta_start + meta_length;
+        let channel = &frame[HEADER_LENGTH..meta_start];
+        let meta = &frame[meta_start..payload_start];
+        let payload = &frame[payload_start..];
+
+        match frame_type {
+            MESSAGE => {
+                let channel = match std::str::from_utf8(channel) {
+                    Ok(channel) => channel,
+                    Err(e) => return Decoded::Malformed(DecodeError::Syntax(e.to_string())),
+                };
+
+                let meta = if meta.is_empty() {
+                    Map::new()
+                } else {
+                    match serde_json::from_slice(meta) {
+                        Ok(meta) => meta,
+                        Err(e) => return Decoded::Malformed(e.into()),
+                    }
+                };
+
+                match T::deserialize(MessageDeserializer { channel, meta, payload }) {
+                    Ok(val) => Decoded::Frame(val),
+                    Err(e) => Decoded::Malformed(DecodeError::Data(e.to_string())),
+                }
+            }
+            CONTROL => match serde_json::from_slice(payload) {
+                Ok(val) => Decoded::Frame(val),
+                Err(e) => Decoded::Malformed(e.into()),
+            },
+            frame_type => {
+                let reason = format!("unknown frame type: {}", frame_type);
+                Decoded::Malformed(DecodeError::Syntax(reason))
+            }
+        }
+    }
+
+    fn encode<T: Serialize>(t: &T) -> Result<Bytes, EncodeError> {
+        let (frame_type, channel, meta, payload) = match t.serialize(MessageSerializer) {
+            Ok(message) => {
+                let meta = if message.meta.is_empty() {
+                    Vec::new()
+                } else {
+                    serde_json::to_vec(&message.meta)?
+                };
+                (MESSAGE, message.channel.into_bytes(), meta, message.payload)
+            }
+            Err(NotAMessage) => (CONTROL, Vec::new(), Vec::new(), serde_json::to_vec(t)?),
+        };
+
+        if channel.len() > u16::MAX as usize {
+            return Err(EncodeError(format!("channel exceeds {} bytes", u16::MAX)));
+        }
+
+        if meta.len() > u16::MAX as usize {
+            return Err(EncodeError(format!("meta exceeds {} bytes", u16::MAX)));
+        }
+
+        if payload.len() > u32::MAX as usize {
+            return Err(EncodeError(format!("payload exceeds {} bytes", u32::MAX)));
+        }
+
+        let mut frame = BytesMut::with_capacity(HEADER_LENGTH + channel.len() + meta.len() + payload.len());
+        frame.put_u8(frame_type);
+        frame.put_slice(&(channel.len() as u16).to_be_bytes());
+        frame.put_slice(&(meta.len() as u16).to_be_bytes());
+        frame.put_slice(&(payload.len() as u32).to_be_bytes());
+        frame.put_slice(&channel);
+        frame.put_slice(&meta);
+        frame.put_slice(&payload);
+        Ok(frame.freeze())
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Decoding -
+//     A `MESSAGE` frame is presented to serde as a map:
+//     `{"channel": <str>, "payload": <bytes>, ..meta}`
+// -----------------------------------------------------------------------------
+struct MessageDeserializer<'a> {
+    channel: &'a str,
+    meta: Map<String, Value>,
+    payload: &'a [u8],
+}
+
+impl<'de, 'a> Deserializer<'de> for MessageDeserializer<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(MessageFields {
+            channel: Some(self.channel),
+            payload: Some(self.payload),
+            meta: self.meta.into_iter(),
+            next: None,
+        })
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct
+        map struct enum identifier ignored_any
+    }
+}
+
+// The next value to hand out, set when its key is handed out
+enum NextValue<'a> {
+    Channel(&'a str),
+    Payload(&'a [u8]),
+    Meta(Value),
+}
+
+struct MessageFields<'a> {
+    channel: Option<&'a str>,
+    payload: Option<&'a [u8]>,
+    meta: serde_json::map::IntoIter,
+    next: Option<NextValue<'a>>,
+}
+
+impl<'de, 'a> MapAccess<'de> for MessageFields<'a> {
+    type Error = de::value::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Self::Error> {
+        if let Some(channel) = self.channel.take() {
+            self.next = Some(NextValue::Channel(channel));
+            return seed.deserialize("channel".into_deserializer()).map(Some);
+        }
+
+        if let Some(payload) = self.payload.take() {
+            self.next = Some(NextValue::Payload(payload));
+            return seed.deserialize("payload".into_deserializer()).map(Some);
+        }
+
+        match self.meta.next() {
+            Some((key, value)) => {
+                self.next = Some(NextValue::Meta(value));
+                seed.deserialize(key.into_deserializer()).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Self::Error> {
+        match self.next.take() {
+            Some(NextValue::Channel(channel)) => seed.deserialize(channel.into_deserializer()),
+            Some(NextValue::Payload(payload)) => seed.deserialize(PayloadDeserializer(payload)),
+            Some(NextValue::Meta(value)) => seed.deserialize(value).map_err(de::Error::custom),
+            None => Err(de::Error::custom("value requested before key")),
+        }
+    }
+}
+
+struct PayloadDeserializer<'a>(&'a [u8]);
+
+impl<'de, 'a> Deserializer<'de> for PayloadDeserializer<'a> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_bytes(self.0)
+    }
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 u8 u16 u32 u64 f32 f64 char str string bytes
+        byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct
+        map struct enum identifier ignored_any
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Encoding -
+//     Anything serialized as a struct with a `channel` and a `payload`
+//     field is a `MESSAGE`, everything else is `CONTROL`.
+// -----------------------------------------------------------------------------
+#[derive(Debug)]
+struct NotAMessage;
+
+impl fmt::Display for NotAMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "not a channel message")
+    }
+}
+
+impl std::error::Error for NotAMessage {}
+
+impl ser::Error for NotAMessage {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        NotAMessage
+    }
+}
+
+macro_rules! not_a_message {
+    ($($method:ident $(<$generic:ident>)* ($($arg:ty),*) -> $ret:ty;)*) => {
+        $(
+            fn $method $(<$generic: ?Sized + Serialize>)* (self, $(_: $arg),*) -> Result<$ret, NotAMessage> {
+                Err(NotAMessage)
+            }
+        )*
+    };
+}
+
+struct MessageSerializer;
+
+impl Serializer for MessageSerializer {
+    type Ok = MessageParts;
+    type Error = NotAMessage;
+    type SerializeSeq = Impossible<Self::Ok, NotAMessage>;
+    type SerializeTuple = Impossible<Self::Ok, NotAMessage>;
+    type SerializeTupleStruct = Impossible<Self::Ok, NotAMessage>;
+    type SerializeTupleVariant = Impossible<Self::Ok, NotAMessage>;
+    type SerializeMap = Impossible<Self::Ok, NotAMessage>;
+    type SerializeStruct = MessageFieldsSerializer;
+    type SerializeStructVariant = Impossible<Self::Ok, NotAMessage>;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self::SerializeStruct, NotAMessage> {
+        Ok(MessageFieldsSerializer::default())
+    }
+
+    not_a_message! {
+        serialize_bool(bool) -> Self::Ok;
+        serialize_i8(i8) -> Self::Ok;
+        serialize_i16(i16) -> Self::Ok;
+        serialize_i32(i32) -> Self::Ok;
+        serialize_i64(i64) -> Self::Ok;
+        serialize_u8(u8) -> Self::Ok;
+        serialize_u16(u16) -> Self::Ok;
+        serialize_u32(u32) -> Self::Ok;
+        serialize_u64(u64) -> Self::Ok;
+        serialize_f32(f32) -> Self::Ok;
+        serialize_f64(f64) -> Self::Ok;
+        serialize_char(char) -> Self::Ok;
+        serialize_str(&str) -> Self::Ok;
+        serialize_bytes(&[u8]) -> Self::Ok;
+        serialize_none() -> Self::Ok;
+        serialize_some<T>(&T) -> Self::Ok;
+        serialize_unit() -> Self::Ok;
+        serialize_unit_struct(&'static str) -> Self::Ok;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Self::Ok;
+        serialize_newtype_struct<T>(&'static str, &T) -> Self::Ok;
+        serialize_newtype_variant<T>(&'static str, u32, &'static str, &T) -> Self::Ok;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+struct MessageParts {
+    channel: String,
+    meta: Map<String, Value>,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+struct MessageFieldsSerializer {
+    channel: Option<String>,
+    meta: Map<String, Value>,
+    payload: Option<Vec<u8>>,
+}
+
+impl SerializeStruct for MessageFieldsSerializer {
+    type Ok = MessageParts;
+    type Error = NotAMessage;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), NotAMessage> {
+        match key {
+            "channel" => match value.serialize(FieldSerializer)? {
+                Field::Str(channel) => self.channel = Some(channel),
+                Field::Bytes(_) => return Err(NotAMessage),
+            },
+            "payload" => match value.serialize(FieldSerializer)? {
+                Field::Str(payload) => self.payload = Some(payload.into_bytes()),
+                Field::Bytes(payload) => self.payload = Some(payload),
+            },
+            key => {
+                let value = serde_json::to_value(value).map_err(|_| NotAMessage)?;
+                self.meta.insert(key.to_owned(), value);
+            }
+        }
+        Ok(())
+    }
+
+    fn end(self) -> Result<MessageParts, NotAMessage> {
+        match (self.channel, self.payload) {
+            (Some(channel), Some(payload)) => Ok(MessageParts { channel, meta: self.meta, payload }),
+            _ => Err(NotAMessage),
+        }
+    }
+}
+
+enum Field {
+    Str(String),
+    Bytes(Vec<u8>),
+}
+
+struct FieldSerializer;
+
+impl Serializer for FieldSerializer {
+    type Ok = Field;
+    type Error = NotAMessage;
+    type SerializeSeq = Impossible<Field, NotAMessage>;
+    type SerializeTuple = Impossible<Field, NotAMessage>;
+    type SerializeTupleStruct = Impossible<Field, NotAMessage>;
+    type SerializeTupleVariant = Impossible<Field, NotAMessage>;
+    type SerializeMap = Impossible<Field, NotAMessage>;
+    type SerializeStruct = Impossible<Field, NotAMessage>;
+    type SerializeStructVariant = Impossible<Field, NotAMessage>;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Field, NotAMessage> {
+        Ok(Field::Str(v.to_owned()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Field, NotAMessage> {
+        Ok(Field::Bytes(v.to_vec()))
+    }
+
+    not_a_message! {
+        serialize_bool(bool) -> Field;
+        serialize_i8(i8) -> Field;
+        serialize_i16(i16) -> Field;
+        serialize_i32(i32) -> Field;
+        serialize_i64(i64) -> Field;
+        serialize_u8(u8) -> Field;
+        serialize_u16(u16) -> Field;
+        serialize_u32(u32) -> Field;
+        serialize_u64(u64) -> Field;
+        serialize_f32(f32) -> Field;
+        serialize_f64(f64) -> Field;
+        serialize_char(char) -> Field;
+        serialize_none() -> Field;
+        serialize_some<T>(&T) -> Field;
+        serialize_unit() -> Field;
+        serialize_unit_struct(&'static str) -> Field;
+        serialize_unit_variant(&'static str, u32, &'static str) -> Field;
+        serialize_newtype_struct<T>(&'static str, &T) -> Field;
+        serialize_newtype_variant<T>(&'static str, u32, &'static str, &T) -> Field;
+        serialize_seq(Option<usize>) -> Self::SerializeSeq;
+        serialize_tuple(usize) -> Self::SerializeTuple;
+        serialize_tuple_struct(&'static str, usize) -> Self::SerializeTupleStruct;
+        serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Self::SerializeTupleVariant;
+        serialize_map(Option<usize>) -> Self::SerializeMap;
+        serialize_struct(&'static str, usize) -> Self::SerializeStruct;
+        serialize_struct_variant(&'static str, u32, &'static str, usize) -> Self::SerializeStructVariant;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{AckMessage, Metadata, PubMessage, PublishRequest};
+
+    fn message() -> PubMessage {
+        let mut message = PubMessage::new("orders.created", Bytes::from(&b"\xff\x00{\n"[..]));
+        message.id = Some("1".into());
+        message.content_type = Some("application/octet-stream".into());
+        message.headers.insert("trace".into(), "abc".into());
+        message.metadata = Some(Metadata::new(7));
+        message
+    }
+
+    fn decode<T: DeserializeOwned>(codec: &mut BinaryCodec, buf: &mut BytesMut) -> T {
+        match codec.decode(buf) {
+            Decoded::Frame(val) => val,
+            Decoded::Malformed(e) => panic!("malformed frame: {}", e),
+            Decoded::Incomplete => panic!("incomplete frame"),
+        }
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let message = message();
+        let frame = BinaryCodec::encode(&message).unwrap();
+        assert_eq!(frame[0], MESSAGE);
+
+        let mut codec = BinaryCodec::new(1024);
+        let mut buf = BytesMut::from(&frame[..]);
+        let decoded: PubMessage = decode(&mut codec, &mut buf);
+        assert!(buf.is_empty());
+
+        assert_eq!(decoded.id, message.id);
+        assert_eq!(decoded.channel, message.channel);
+        assert_eq!(decoded.payload, message.payload);
+        assert_eq!(decoded.content_type, message.content_type);
+        assert_eq!(decoded.headers, message.headers);
+
+        let (metadata, decoded) = (message.metadata.unwrap(), decoded.metadata.unwrap());
+        assert_eq!(decoded.id, metadata.id);
+        assert_eq!(decoded.received_at, metadata.received_at);
+        assert_eq!(decoded.publisher, metadata.publisher);
+    }
+
+    #[test]
+    fn message_without_meta() {
+        let frame = BinaryCodec::encode(&PubMessage::new("orders", "hi")).unwrap();
+        assert_eq!(u16::from_be_bytes([frame[3], frame[4]]), 0);
+
+        let mut buf = BytesMut::from(&frame[..]);
+        match BinaryCodec::new(1024).decode(&mut buf) {
+            Decoded::Frame(PublishRequest::Publish(message)) => assert_eq!(&message.payload[..], b"hi"),
+            _ => panic!("expected a publish"),
+        }
+    }
+
+    #[test]
+    fn control_round_trip() {
+        let frame = BinaryCodec::encode(&AckMessage::nack(Some("1".into()), "invalid")).unwrap();
+        assert_eq!(frame[0], CONTROL);
+        assert_eq!(u16::from_be_bytes([frame[1], frame[2]]), 0);
+
+        let mut buf = BytesMut::from(&frame[..]);
+        let ack: AckMessage = decode(&mut BinaryCodec::new(1024), &mut buf);
+        assert!(!ack.is_ack());
+        assert_eq!(ack.id(), Some("1"));
+        assert_eq!(ack.reason(), Some("invalid"));
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let frame = BinaryCodec::encode(&message()).unwrap();
+        let mut codec = BinaryCodec::new(1024);
+        let mut buf = BytesMut::new();
+
+        for chunk in [&frame[..4], &frame[4..HEADER_LENGTH + 2], &frame[HEADER_LENGTH + 2..frame.len() - 1]].iter() {
+            buf.extend_from_slice(chunk);
+            assert!(matches!(codec.decode::<PubMessage>(&mut buf), Decoded::Incomplete));
+        }
+
+        buf.extend_from_slice(&frame[frame.len() - 1..]);
+        let decoded: PubMessage = decode(&mut codec, &mut buf);
+        assert_eq!(decoded.channel, "orders.created");
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let frame = BinaryCodec::encode(&message()).unwrap();
+        let mut buf = BytesMut::from(&frame[..HEADER_LENGTH]);
+
+        // Known from the header alone
+        match BinaryCodec::new(frame.len() - 1).decode::<PubMessage>(&mut buf) {
+            Decoded::Malformed(e) => assert!(e.is_fatal()),
+            _ => panic!("expected frame too long"),
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn unknown_frame_type() {
+        let mut frame = BinaryCodec::encode(&AckMessage::ack(None)).unwrap().to_vec();
+        frame[0] = 9;
+        let mut buf = BytesMut::from(&frame[..]);
+        buf.extend_from_slice(&BinaryCodec::encode(&AckMessage::ack(None)).unwrap());
+
+        let mut codec = BinaryCodec::new(1024);
+        match codec.decode::<AckMessage>(&mut buf) {
+            Decoded::Malformed(e) => {
+                assert!(!e.is_fatal());
+                assert!(e.to_string().contains("unknown frame type: 9"));
+            }
+            _ => panic!("expected a malformed frame"),
+        }
+
+        // The frame is skipped
+        let ack: AckMessage = decode(&mut codec, &mut buf);
+        assert!(ack.is_ack());
+    }
+}
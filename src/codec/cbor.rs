@@ -0,0 +1,84 @@
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{length_prefixed, split_length_prefixed, Codec, Decoded, DecodeError, EncodeError};
+
+/// Length prefixed CBOR.
+pub struct CborCodec {
+    max_frame_length: usize,
+}
+
+impl Codec for CborCodec {
+    fn new(max_frame_length: usize) -> Self {
+        Self { max_frame_length }
+    }
+
+    fn decode<T: DeserializeOwned>(&mut self, buf: &mut BytesMut) -> Decoded<T> {
+        let body = match split_length_prefixed(buf, self.max_frame_length) {
+            Decoded::Frame(body) => body,
+            Decoded::Incomplete => return Decoded::Incomplete,
+            Decoded::Malformed(e) => return Decoded::Malformed(e),
+        };
+
+        match serde_cbor::from_slice(&body) {
+            Ok(val) => Decoded::Frame(val),
+            Err(e) => Decoded::Malformed(e.into()),
+        }
+    }
+
+    fn encode<T: Serialize>(t: &T) -> Result<Bytes, EncodeError> {
+        let body = serde_cbor::to_vec(t).map_err(|e| EncodeError(e.to_string()))?;
+        length_prefixed(body)
+    }
+}
+
+impl From<serde_cbor::Error> for DecodeError {
+    fn from(err: serde_cbor::Error) -> Self {
+        if err.is_data() {
+            DecodeError::Data(err.to_string())
+        } else {
+            DecodeError::Syntax(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{PubMessage, PublishRequest};
+
+    fn decode(frame: &[u8]) -> Decoded<PublishRequest> {
+        let mut buf = BytesMut::from(frame);
+        let decoded = CborCodec::new(1024).decode(&mut buf);
+        assert!(buf.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        let mut message = PubMessage::new("orders.created", Bytes::from(&b"\xff\x00{\n"[..]));
+        message.id = Some("1".into());
+        message.headers.insert("trace".into(), "abc".into());
+
+        // Decoded through the untagged `PublishRequest`, the payload stays raw bytes
+        match decode(&CborCodec::encode(&message).unwrap()) {
+            Decoded::Frame(PublishRequest::Publish(decoded)) => {
+                assert_eq!(decoded.id, message.id);
+                assert_eq!(decoded.channel, message.channel);
+                assert_eq!(decoded.payload, message.payload);
+                assert_eq!(decoded.headers, message.headers);
+            }
+            _ => panic!("expected a publish"),
+        }
+    }
+
+    #[test]
+    fn invalid_publish_keeps_id() {
+        let frame = CborCodec::encode(&serde_json::json!({"id": "2", "channel": 7})).unwrap();
+        match decode(&frame) {
+            Decoded::Frame(PublishRequest::Invalid(invalid)) => assert_eq!(invalid.id.as_deref(), Some("2")),
+            _ => panic!("expected an invalid publish"),
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{Codec, Decoded, DecodeError, EncodeError};
+use crate::BUFFER_SIZE;
+
+/// Newline delimited JSON.
+pub struct LineCodec {
+    // How far into the buffer the previous search for
+    // a newline got, so the next search can resume from there.
+    scanned: usize,
+    max_frame_length: usize,
+}
+
+impl Codec for LineCodec {
+    const JSON_LINES: bool = true;
+
+    fn new(max_frame_length: usize) -> Self {
+        Self {
+            scanned: 0,
+            max_frame_length,
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&mut self, buf: &mut BytesMut) -> Decoded<T> {
+        let start = self.scanned.min(buf.len());
+        let p = buf[start..].iter().position(|b| b == &b'\n');
+        match p {
+            None => {
+                self.scanned = buf.len();
+
+                // No point in waiting for the rest of the frame.
+                if self.scanned > self.max_frame_length {
+                    self.scanned = 0;
+                    buf.clear();
+                    return Decoded::Malformed(DecodeError::FrameTooLong(self.max_frame_length));
+                }
+
+                Decoded::Incomplete
+            }
+            Some(p) => {
+                let n = start + p;
+                self.scanned = 0;
+
+                let frame = buf.split_to(n).freeze();
+                buf.advance(1); // Skip the newline char
+                buf.reserve(BUFFER_SIZE); // Make sure the buffer can hold more data
+
+                if n > self.max_frame_length {
+                    return Decoded::Malformed(DecodeError::FrameTooLong(self.max_frame_length));
+                }
+
+                match serde_json::from_slice(&frame) {
+                    Ok(val) => Decoded::Frame(val),
+                    Err(e) => Decoded::Malformed(e.into()),
+                }
+            }
+        }
+    }
+
+    fn encode<T: Serialize>(t: &T) -> Result<Bytes, EncodeError> {
+        let mut payload = serde_json::to_vec(t)?;
+        payload.push(b'\n');
+        Ok(Bytes::from(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{AckMessage, PublishRequest, Subscribe};
+
+    fn subscribe(channel: &str) -> Vec<u8> {
+        format!("{{\"channel\":\"{}\"}}\n", channel).into_bytes()
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let mut codec = LineCodec::new(1024);
+        let frame = subscribe("orders");
+        let mut buf = BytesMut::from(&frame[..10]);
+
+        assert!(matches!(codec.decode::<Subscribe>(&mut buf), Decoded::Incomplete));
+        assert_eq!(codec.scanned, 10);
+
+        // The next search starts where the last one left off
+        buf.extend_from_slice(&frame[10..]);
+        buf.extend_from_slice(&subscribe("users")[..4]);
+        match codec.decode::<Subscribe>(&mut buf) {
+            Decoded::Frame(subscribe) => assert_eq!(subscribe.channel, "orders"),
+            _ => panic!("expected a frame"),
+        }
+        assert_eq!(codec.scanned, 0);
+        assert_eq!(&buf[..], &subscribe("users")[..4]);
+    }
+
+    #[test]
+    fn frame_too_long_without_newline() {
+        let mut codec = LineCodec::new(16);
+        let mut buf = BytesMut::from(&subscribe("orders.created")[..20]);
+
+        match codec.decode::<Subscribe>(&mut buf) {
+            Decoded::Malformed(e) => {
+                assert!(e.is_fatal());
+                assert!(matches!(e, DecodeError::FrameTooLong(16)));
+            }
+            _ => panic!("expected frame too long"),
+        }
+        assert!(buf.is_empty());
+        assert_eq!(codec.scanned, 0);
+    }
+
+    #[test]
+    fn frame_too_long_with_newline() {
+        let mut codec = LineCodec::new(16);
+        let mut buf = BytesMut::from(&subscribe("orders.created")[..]);
+        buf.extend_from_slice(&subscribe("a"));
+
+        assert!(matches!(
+            codec.decode::<Subscribe>(&mut buf),
+            Decoded::Malformed(DecodeError::FrameTooLong(16))
+        ));
+
+        // Only the long frame is discarded
+        match codec.decode::<Subscribe>(&mut buf) {
+            Decoded::Frame(subscribe) => assert_eq!(subscribe.channel, "a"),
+            _ => panic!("expected a frame"),
+        }
+    }
+
+    #[test]
+    fn malformed_frames() {
+        let mut codec = LineCodec::new(1024);
+        let mut buf = BytesMut::from(&b"{\"channel\":\n{\"unknown\":1}\n"[..]);
+        buf.extend_from_slice(&subscribe("orders"));
+
+        assert!(matches!(codec.decode::<Subscribe>(&mut buf), Decoded::Malformed(DecodeError::Syntax(_))));
+        assert!(matches!(codec.decode::<Subscribe>(&mut buf), Decoded::Malformed(DecodeError::Data(_))));
+        assert!(matches!(codec.decode::<Subscribe>(&mut buf), Decoded::Frame(_)));
+        assert!(matches!(codec.decode::<Subscribe>(&mut buf), Decoded::Incomplete));
+    }
+
+    #[test]
+    fn invalid_publish_keeps_id() {
+        let mut codec = LineCodec::new(1024);
+        let mut buf = BytesMut::from(&b"{\"id\":\"1\",\"channel\":7}\n{\"id\":\n"[..]);
+
+        // Valid JSON but not a message, the nack can still carry the id
+        match codec.decode::<PublishRequest>(&mut buf) {
+            Decoded::Frame(PublishRequest::Invalid(invalid)) => {
+                let nack = AckMessage::nack(invalid.id, "invalid publish message");
+                assert_eq!(nack.id(), Some("1"));
+            }
+            _ => panic!("expected an invalid publish"),
+        }
+
+        // Not even JSON, there is no id to echo
+        assert!(matches!(codec.decode::<PublishRequest>(&mut buf), Decoded::Malformed(DecodeError::Syntax(_))));
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::error::Category;
+
+mod binary;
+mod line;
+#[cfg(feature = "msgpack")]
+mod msgpack;
+#[cfg(feature = "cbor")]
+mod cbor;
+
+pub use binary::BinaryCodec;
+pub use line::LineCodec;
+#[cfg(feature = "msgpack")]
+pub use msgpack::MsgPackCodec;
+#[cfg(feature = "cbor")]
+pub use cbor::CborCodec;
+
+/// Framing and serialization of messages on a connection.
+/// A codec instance holds the decoding state for a single connection,
+/// whereas encoding is stateless.
+pub trait Codec {
+    /// Frames are newline delimited JSON, the format messages are
+    /// published in, so deliveries can be written without re-encoding.
+    const JSON_LINES: bool = false;
+
+    fn new(max_frame_length: usize) -> Self;
+
+    /// Decode the next frame in the buffer, removing it from the buffer.
+    fn decode<T: DeserializeOwned>(&mut self, buf: &mut BytesMut) -> Decoded<T>;
+
+    fn encode<T: Serialize>(t: &T) -> Result<Bytes, EncodeError>;
+}
+
+/// The codec used by a listener's connections,
+/// see `BrokerBuilder::publisher_codec` and the `listeners.*_codec` settings.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// Newline delimited JSON, see `LineCodec`
+    #[default]
+    Line,
+    /// See `BinaryCodec`
+    Binary,
+    /// See `MsgPackCodec`
+    #[cfg(feature = "msgpack")]
+    #[serde(rename = "msgpack")]
+    MsgPack,
+    /// See `CborCodec`
+    #[cfg(feature = "cbor")]
+    Cbor,
+}
+
+/// The result of trying to decode a single frame.
+pub enum Decoded<T> {
+    /// There is no complete frame in the buffer yet
+    Incomplete,
+    /// A complete frame was decoded
+    Frame(T),
+    /// A complete frame was removed from the buffer
+    /// but could not be decoded
+    Malformed(DecodeError),
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    /// The frame is not valid in the codec's format
+    Syntax(String),
+    /// The frame is valid but not the expected message
+    Data(String),
+    /// The frame exceeds the maximum frame length.
+    /// The frame is discarded and the connection should be closed.
+    FrameTooLong(usize),
+}
+
+impl DecodeError {
+    /// The connection can not recover from this error
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            DecodeError::FrameTooLong(_) => true,
+            DecodeError::Syntax(_) | DecodeError::Data(_) => false,
+        }
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Syntax(reason) => write!(f, "malformed frame: {}", reason),
+            DecodeError::Data(reason) => write!(f, "unexpected message: {}", reason),
+            DecodeError::FrameTooLong(max) => write!(f, "frame exceeds {} bytes", max),
+        }
+    }
+}
+
+impl From<serde_json::Error> for DecodeError {
+    fn from(err: serde_json::Error) -> Self {
+        match err.classify() {
+            Category::Data => DecodeError::Data(err.to_string()),
+            Category::Io | Category::Syntax | Category::Eof => DecodeError::Syntax(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct EncodeError(String);
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "failed to encode frame: {}", self.0)
+    }
+}
+
+impl From<serde_json::Error> for EncodeError {
+    fn from(err: serde_json::Error) -> Self {
+        EncodeError(err.to_string())
+    }
+}
+
+// -----------------------------------------------------------------------------
+//     - Length prefix -
+//     Framing for serde formats that are not self-delimiting on a stream:
+//     the length of the body as a u32 (big endian), followed by the body.
+// -----------------------------------------------------------------------------
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+const LENGTH_PREFIX: usize = 4;
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn split_length_prefixed(buf: &mut BytesMut, max_frame_length: usize) -> Decoded<Bytes> {
+    use crate::BUFFER_SIZE;
+
+    if buf.len() < LENGTH_PREFIX {
+        return Decoded::Incomplete;
+    }
+
+    let body_length = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+    let frame_length = LENGTH_PREFIX + body_length;
+
+    if frame_length > max_frame_length {
+        buf.clear();
+        return Decoded::Malformed(DecodeError::FrameTooLong(max_frame_length));
+    }
+
+    if buf.len() < frame_length {
+        buf.reserve(frame_length - buf.len());
+        return Decoded::Incomplete;
+    }
+
+    buf.advance(LENGTH_PREFIX);
+    let body = buf.split_to(body_length).freeze();
+    buf.reserve(BUFFER_SIZE); // Make sure the buffer can hold more data
+    Decoded::Frame(body)
+}
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+fn length_prefixed(body: Vec<u8>) -> Result<Bytes, EncodeError> {
+    use bytes::BufMut;
+
+    if body.len() > u32::MAX as usize {
+        return Err(EncodeError(format!("frame exceeds {} bytes", u32::MAX)));
+    }
+
+    let mut frame = BytesMut::with_capacity(LENGTH_PREFIX + body.len());
+    frame.put_slice(&(body.len() as u32).to_be_bytes());
+    frame.put_slice(&body);
+    Ok(frame.freeze())
+}
@@ -0,0 +1,87 @@
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use super::{length_prefixed, split_length_prefixed, Codec, Decoded, DecodeError, EncodeError};
+
+/// Length prefixed MessagePack.
+/// Structs are encoded as maps so messages can be told apart by field name.
+pub struct MsgPackCodec {
+    max_frame_length: usize,
+}
+
+impl Codec for MsgPackCodec {
+    fn new(max_frame_length: usize) -> Self {
+        Self { max_frame_length }
+    }
+
+    fn decode<T: DeserializeOwned>(&mut self, buf: &mut BytesMut) -> Decoded<T> {
+        let body = match split_length_prefixed(buf, self.max_frame_length) {
+            Decoded::Frame(body) => body,
+            Decoded::Incomplete => return Decoded::Incomplete,
+            Decoded::Malformed(e) => return Decoded::Malformed(e),
+        };
+
+        match rmp_serde::from_slice(&body) {
+            Ok(val) => Decoded::Frame(val),
+            Err(e) => Decoded::Malformed(e.into()),
+        }
+    }
+
+    fn encode<T: Serialize>(t: &T) -> Result<Bytes, EncodeError> {
+        let body = rmp_serde::to_vec_named(t).map_err(|e| EncodeError(e.to_string()))?;
+        length_prefixed(body)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for DecodeError {
+    fn from(err: rmp_serde::decode::Error) -> Self {
+        use rmp_serde::decode::Error::*;
+
+        match err {
+            // Raised by serde when the data does not fit the message
+            Syntax(_) | TypeMismatch(_) | OutOfRange | LengthMismatch(_) => DecodeError::Data(err.to_string()),
+            _ => DecodeError::Syntax(err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::messages::{PubMessage, PublishRequest};
+
+    fn decode(frame: &[u8]) -> Decoded<PublishRequest> {
+        let mut buf = BytesMut::from(frame);
+        let decoded = MsgPackCodec::new(1024).decode(&mut buf);
+        assert!(buf.is_empty());
+        decoded
+    }
+
+    #[test]
+    fn publish_round_trip() {
+        let mut message = PubMessage::new("orders.created", Bytes::from(&b"\xff\x00{\n"[..]));
+        message.id = Some("1".into());
+        message.headers.insert("trace".into(), "abc".into());
+
+        // Decoded through the untagged `PublishRequest`, the payload stays raw bytes
+        match decode(&MsgPackCodec::encode(&message).unwrap()) {
+            Decoded::Frame(PublishRequest::Publish(decoded)) => {
+                assert_eq!(decoded.id, message.id);
+                assert_eq!(decoded.channel, message.channel);
+                assert_eq!(decoded.payload, message.payload);
+                assert_eq!(decoded.headers, message.headers);
+            }
+            _ => panic!("expected a publish"),
+        }
+    }
+
+    #[test]
+    fn invalid_publish_keeps_id() {
+        let frame = MsgPackCodec::encode(&serde_json::json!({"id": "2", "channel": 7})).unwrap();
+        match decode(&frame) {
+            Decoded::Frame(PublishRequest::Invalid(invalid)) => assert_eq!(invalid.id.as_deref(), Some("2")),
+            _ => panic!("expected an invalid publish"),
+        }
+    }
+}
@@ -0,0 +1,687 @@
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+use crate::codec::Format;
+use crate::options::{Backpressure, Options, SlowConsumerPolicy};
+use crate::MAX_FRAME_LENGTH;
+
+// Environment variables are the config keys prefixed with `PUBSUB_`,
+// upper case and with `.` replaced by `_`: `PUBSUB_BATCH_THRESHOLD`.
+// Other tools use the same prefix (`PUBSUB_EMULATOR_HOST`, `PUBSUB_PROJECT_ID`
+// for Google Cloud Pub/Sub), so variables that are not settings are ignored.
+const ENV_PREFIX: &str = "PUBSUB_";
+const CONFIG_ENV: &str = "PUBSUB_CONFIG";
+
+pub const USAGE: &str = "\
+Usage: pubsub [--config <file>] [--<key> <value>]...
+
+Settings are read from the TOML config file (--config or PUBSUB_CONFIG),
+then environment variables (PUBSUB_THREADS, PUBSUB_LISTENERS_PUBLISHER, ...)
+and finally the command line, later ones taking precedence.
+PUBSUB_ variables that are not settings are ignored.
+
+Keys (flags replace `.` and `_` with `-`, e.g. --batch-threshold):
+    threads                     worker threads
+    listeners.publisher         publisher port address
+    listeners.subscriber        subscriber port address
+    listeners.client            client port address
+    listeners.publisher_codec   line, binary, msgpack or cbor (the last
+    listeners.subscriber_codec  two if built with the feature of that name)
+    listeners.client_codec
+    batch.threshold             bytes buffered before a batch is published
+    batch.timeout_ms            publish a partial batch after this long
+    limits.max_frame_length     largest accepted frame in bytes
+    limits.max_malformed_frames disconnect after this many malformed frames
+    limits.max_write_buffer     bytes queued per subscriber
+    limits.broadcast_capacity   bytes a thread may fall behind before
+                                publishers back off
+    slow_consumer_policy        disconnect, drop_oldest, drop_newest or pause
+    backpressure                pause or nack
+    heartbeat.interval_secs     ping idle clients after this long
+    heartbeat.idle_timeout_secs disconnect clients idle for this long
+    heartbeat.publisher         heartbeats on the publisher port
+    heartbeat.subscriber        heartbeats on the subscriber port
+    heartbeat.client            heartbeats on the client port
+    shutdown.drain_timeout_ms   time given to connections to receive what is
+                                queued for them when shutting down
+    features.publisher          enable the publisher port
+    features.subscriber         enable the subscriber port
+    features.client             enable the client port
+    features.heartbeats         heartbeats on any port
+
+Limits and heartbeat timeouts can be turned off with `none`.
+Heartbeats are off on the publisher and subscriber ports by default,
+as existing clients of those ports do not answer pings.
+";
+
+/// Broker configuration.
+///
+/// Starts out with the defaults, overridden by the config file,
+/// then environment variables and finally command line flags.
+/// See `USAGE` for the available settings.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub threads: usize,
+    pub listeners: Listeners,
+    pub batch: Batch,
+    pub limits: Limits,
+    pub slow_consumer_policy: SlowConsumerPolicy,
+    pub backpressure: Backpressure,
+    pub heartbeat: Heartbeat,
+    pub shutdown: Shutdown,
+    pub features: Features,
+}
+
+/// Addresses to listen on, and the codec of each port
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Listeners {
+    pub publisher: String,
+    pub subscriber: String,
+    pub client: String,
+    pub publisher_codec: Format,
+    pub subscriber_codec: Format,
+    pub client_codec: Format,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Batch {
+    /// Publish the batch once this many bytes are buffered
+    pub threshold: usize,
+    /// Publish whatever is buffered this often
+    pub timeout_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    pub max_frame_length: usize,
+    #[serde(deserialize_with = "limit")]
+    pub max_malformed_frames: Option<usize>,
+    #[serde(deserialize_with = "limit")]
+    pub max_write_buffer: Option<usize>,
+    #[serde(deserialize_with = "limit")]
+    pub broadcast_capacity: Option<usize>,
+}
+
+/// Heartbeats, and the ports they are used on.
+/// Only on the client port by default, clients of the publisher and
+/// subscriber ports predate pings and would be disconnected as idle.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Heartbeat {
+    #[serde(deserialize_with = "limit")]
+    pub interval_secs: Option<u64>,
+    #[serde(deserialize_with = "limit")]
+    pub idle_timeout_secs: Option<u64>,
+    pub publisher: bool,
+    pub subscriber: bool,
+    pub client: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Shutdown {
+    pub drain_timeout_ms: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Features {
+    pub publisher: bool,
+    pub subscriber: bool,
+    pub client: bool,
+    pub heartbeats: bool,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// `--help` was passed, the caller should print `USAGE`
+    Help,
+    /// The config file could not be read
+    Io(String, String),
+    /// The config file is not valid
+    File(String, String),
+    UnknownKey(String),
+    MissingValue(String),
+    InvalidValue(String, String),
+    /// The settings are valid on their own but not together
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Help => write!(f, "{}", USAGE),
+            ConfigError::Io(path, reason) => write!(f, "failed to read {}: {}", path, reason),
+            ConfigError::File(path, reason) => write!(f, "invalid config file {}: {}", path, reason),
+            ConfigError::UnknownKey(key) => write!(f, "unknown setting: {}", key),
+            ConfigError::MissingValue(key) => write!(f, "missing value for {}", key),
+            ConfigError::InvalidValue(key, value) => write!(f, "invalid value for {}: {:?}", key, value),
+            ConfigError::Invalid(reason) => write!(f, "invalid config: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the config from the file, environment variables
+    /// and command line arguments (without the program name).
+    pub fn load<A, E>(args: A, vars: E) -> Result<Self, ConfigError>
+    where
+        A: IntoIterator<Item = String>,
+        E: IntoIterator<Item = (String, String)>,
+    {
+        let args = parse_args(args)?;
+        let mut path = args.config;
+        let mut vars = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect::<Vec<_>>();
+
+        if let Some(i) = vars.iter().position(|(name, _)| name == CONFIG_ENV) {
+            let (_, file) = vars.remove(i);
+            path = path.or(Some(file));
+        }
+
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None => Self::default(),
+        };
+
+        for (name, value) in vars {
+            if let Some(key) = env_key(&name) {
+                config.set(key, &value)?;
+            }
+        }
+
+        for (key, value) in args.settings {
+            config.set(&key, &value)?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::Io(path.to_string(), e.to_string()))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::File(path.to_string(), e.to_string()))
+    }
+
+    /// Override a single setting, e.g. `set("batch.threshold", "512")`
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "threads" => self.threads = parse(key, value)?,
+            "listeners.publisher" => self.listeners.publisher = value.to_string(),
+            "listeners.subscriber" => self.listeners.subscriber = value.to_string(),
+            "listeners.client" => self.listeners.client = value.to_string(),
+            "listeners.publisher_codec" => self.listeners.publisher_codec = parse_variant(key, value)?,
+            "listeners.subscriber_codec" => self.listeners.subscriber_codec = parse_variant(key, value)?,
+            "listeners.client_codec" => self.listeners.client_codec = parse_variant(key, value)?,
+            "batch.threshold" => self.batch.threshold = parse(key, value)?,
+            "batch.timeout_ms" => self.batch.timeout_ms = parse(key, value)?,
+            "limits.max_frame_length" => self.limits.max_frame_length = parse(key, value)?,
+            "limits.max_malformed_frames" => self.limits.max_malformed_frames = parse_limit(key, value)?,
+            "limits.max_write_buffer" => self.limits.max_write_buffer = parse_limit(key, value)?,
+            "limits.broadcast_capacity" => self.limits.broadcast_capacity = parse_limit(key, value)?,
+            "slow_consumer_policy" => self.slow_consumer_policy = parse_variant(key, value)?,
+            "backpressure" => self.backpressure = parse_variant(key, value)?,
+            "heartbeat.interval_secs" => self.heartbeat.interval_secs = parse_limit(key, value)?,
+            "heartbeat.idle_timeout_secs" => self.heartbeat.idle_timeout_secs = parse_limit(key, value)?,
+            "heartbeat.publisher" => self.heartbeat.publisher = parse(key, value)?,
+            "heartbeat.subscriber" => self.heartbeat.subscriber = parse(key, value)?,
+            "heartbeat.client" => self.heartbeat.client = parse(key, value)?,
+            "shutdown.drain_timeout_ms" => self.shutdown.drain_timeout_ms = parse(key, value)?,
+            "features.publisher" => self.features.publisher = parse(key, value)?,
+            "features.subscriber" => self.features.subscriber = parse(key, value)?,
+            "features.client" => self.features.client = parse(key, value)?,
+            "features.heartbeats" => self.features.heartbeats = parse(key, value)?,
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// The `PUBSUB_` environment variables that are not settings,
+    /// which `load` ignores. Worth a warning in case of a typo.
+    pub fn unknown_env<E>(vars: E) -> Vec<String>
+    where
+        E: IntoIterator<Item = (String, String)>,
+    {
+        vars.into_iter()
+            .map(|(name, _)| name)
+            .filter(|name| name.starts_with(ENV_PREFIX) && name != CONFIG_ENV && env_key(name).is_none())
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.threads == 0 {
+            return Err(ConfigError::Invalid("threads must be at least 1".into()));
+        }
+
+        if self.batch.threshold == 0 {
+            return Err(ConfigError::Invalid("batch.threshold must be at least 1".into()));
+        }
+
+        if self.batch.timeout_ms == 0 {
+            return Err(ConfigError::Invalid("batch.timeout_ms must be at least 1".into()));
+        }
+
+        if self.limits.max_frame_length == 0 {
+            return Err(ConfigError::Invalid("limits.max_frame_length must be at least 1".into()));
+        }
+
+        if !(self.features.publisher || self.features.subscriber || self.features.client) {
+            return Err(ConfigError::Invalid("at least one listener must be enabled".into()));
+        }
+
+        let listeners = [
+            (self.features.publisher, "listeners.publisher", &self.listeners.publisher),
+            (self.features.subscriber, "listeners.subscriber", &self.listeners.subscriber),
+            (self.features.client, "listeners.client", &self.listeners.client),
+        ];
+        for (enabled, key, addr) in listeners.iter() {
+            if *enabled && addr.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError::InvalidValue(key.to_string(), addr.to_string()));
+            }
+        }
+
+        if let (Some(interval), Some(idle_timeout)) = (self.heartbeat.interval_secs, self.heartbeat.idle_timeout_secs) {
+            if self.features.heartbeats && idle_timeout <= interval {
+                let reason = "heartbeat.idle_timeout_secs must be longer than heartbeat.interval_secs";
+                return Err(ConfigError::Invalid(reason.into()));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn publish_timeout(&self) -> Duration {
+        Duration::from_millis(self.batch.timeout_ms)
+    }
+
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_millis(self.shutdown.drain_timeout_ms)
+    }
+
+    /// Options shared by every port, without heartbeats
+    pub fn options(&self) -> Options {
+        Options {
+            max_frame_length: self.limits.max_frame_length,
+            max_malformed_frames: self.limits.max_malformed_frames,
+            max_write_buffer: self.limits.max_write_buffer,
+            slow_consumer_policy: self.slow_consumer_policy,
+            backpressure: self.backpressure,
+            ..Options::default()
+        }
+    }
+
+    pub fn publisher_options(&self) -> Options {
+        self.port_options(self.heartbeat.publisher)
+    }
+
+    pub fn subscriber_options(&self) -> Options {
+        self.port_options(self.heartbeat.subscriber)
+    }
+
+    pub fn client_options(&self) -> Options {
+        self.port_options(self.heartbeat.client)
+    }
+
+    fn port_options(&self, heartbeats: bool) -> Options {
+        if !(self.features.heartbeats && heartbeats) {
+            return self.options();
+        }
+
+        Options {
+            heartbeat_interval: self.heartbeat.interval_secs.map(Duration::from_secs),
+            idle_timeout: self.heartbeat.idle_timeout_secs.map(Duration::from_secs),
+            ..self.options()
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            threads: 8,
+            listeners: Listeners::default(),
+            batch: Batch::default(),
+            limits: Limits::default(),
+            slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+            backpressure: Backpressure::Pause,
+            heartbeat: Heartbeat::default(),
+            shutdown: Shutdown::default(),
+            features: Features::default(),
+        }
+    }
+}
+
+impl Default for Listeners {
+    fn default() -> Self {
+        Self {
+            publisher: "127.0.0.1:8000".into(),
+            subscriber: "127.0.0.1:9000".into(),
+            client: "127.0.0.1:7000".into(),
+            publisher_codec: Format::Line,
+            subscriber_codec: Format::Line,
+            client_codec: Format::Line,
+        }
+    }
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self {
+            threshold: 256,
+            timeout_ms: 20,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_length: MAX_FRAME_LENGTH,
+            max_malformed_frames: None,
+            // Disconnect subscribers that fall too far behind
+            // rather than buffering for them indefinitely.
+            max_write_buffer: Some(64 * 1024 * 1024),
+            // Publishers back off once a subscriber thread is this far behind
+            broadcast_capacity: Some(64 * 1024 * 1024),
+        }
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval_secs: Some(30),
+            idle_timeout_secs: Some(90),
+            publisher: false,
+            subscriber: false,
+            client: true,
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self { drain_timeout_ms: 5000 }
+    }
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self {
+            publisher: true,
+            subscriber: true,
+            client: true,
+            heartbeats: true,
+        }
+    }
+}
+
+// Every key accepted by `Config::set`
+const KEYS: &[&str] = &[
+    "threads",
+    "listeners.publisher",
+    "listeners.subscriber",
+    "listeners.client",
+    "listeners.publisher_codec",
+    "listeners.subscriber_codec",
+    "listeners.client_codec",
+    "batch.threshold",
+    "batch.timeout_ms",
+    "limits.max_frame_length",
+    "limits.max_malformed_frames",
+    "limits.max_write_buffer",
+    "limits.broadcast_capacity",
+    "slow_consumer_policy",
+    "backpressure",
+    "heartbeat.interval_secs",
+    "heartbeat.idle_timeout_secs",
+    "heartbeat.publisher",
+    "heartbeat.subscriber",
+    "heartbeat.client",
+    "shutdown.drain_timeout_ms",
+    "features.publisher",
+    "features.subscriber",
+    "features.client",
+    "features.heartbeats",
+];
+
+// Environment variables can't tell `.` and `_` apart,
+// so match them against the keys with both replaced.
+fn env_key(name: &str) -> Option<&'static str> {
+    let name = name[ENV_PREFIX.len()..].to_lowercase();
+    KEYS.iter().find(|key| key.replace('.', "_") == name).cloned()
+}
+
+struct Args {
+    config: Option<String>,
+    settings: Vec<(String, String)>,
+}
+
+// `--key value` or `--key=value`, where the key is
+// a config key with `.` and `_` replaced by `-`
+fn parse_args<A: IntoIterator<Item = String>>(args: A) -> Result<Args, ConfigError> {
+    let mut parsed = Args { config: None, settings: Vec::new() };
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "-h" || arg == "--help" {
+            return Err(ConfigError::Help);
+        }
+
+        let flag = match arg.strip_prefix("--") {
+            Some(flag) => flag,
+            None => return Err(ConfigError::UnknownKey(arg)),
+        };
+
+        let (flag, value) = match flag.find('=') {
+            Some(i) => (&flag[..i], Some(flag[i + 1..].to_string())),
+            None => (flag, None),
+        };
+
+        let value = match value.or_else(|| args.next()) {
+            Some(value) => value,
+            None => return Err(ConfigError::MissingValue(format!("--{}", flag))),
+        };
+
+        if flag == "config" {
+            parsed.config = Some(value);
+            continue;
+        }
+
+        match KEYS.iter().find(|key| key.replace(['.', '_'], "-") == flag) {
+            Some(key) => parsed.settings.push((key.to_string(), value)),
+            None => return Err(ConfigError::UnknownKey(format!("--{}", flag))),
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue(key.to_string(), value.to_string()))
+}
+
+// `none` turns the limit off
+fn parse_limit<T: std::str::FromStr>(key: &str, value: &str) -> Result<Option<T>, ConfigError> {
+    if value.eq_ignore_ascii_case("none") {
+        return Ok(None);
+    }
+    parse(key, value).map(Some)
+}
+
+// Enum settings use the same names as in the config file
+fn parse_variant<T: serde::de::DeserializeOwned>(key: &str, value: &str) -> Result<T, ConfigError> {
+    T::deserialize(toml::Value::String(value.to_string()))
+        .map_err(|_| ConfigError::InvalidValue(key.to_string(), value.to_string()))
+}
+
+// A limit in the config file is either a number or "none"
+fn limit<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Limit<T> {
+        Value(T),
+        Off(String),
+    }
+
+    match Limit::deserialize(deserializer)? {
+        Limit::Value(value) => Ok(Some(value)),
+        Limit::Off(ref off) if off.eq_ignore_ascii_case("none") => Ok(None),
+        Limit::Off(off) => Err(D::Error::custom(format!("expected a number or \"none\", found {:?}", off))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    }
+
+    // Removed when dropped
+    struct ConfigFile(std::path::PathBuf);
+
+    impl ConfigFile {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("pubsub-{}-{}.toml", name, std::process::id()));
+            fs::write(&path, contents).unwrap();
+            ConfigFile(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for ConfigFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn precedence() {
+        let file = ConfigFile::new("precedence", "
+            threads = 2
+            [batch]
+            threshold = 100
+            timeout_ms = 5
+        ");
+
+        let config = Config::load(
+            args(&["--threads", "4"]),
+            vars(&[("PUBSUB_CONFIG", file.path()), ("PUBSUB_THREADS", "3"), ("PUBSUB_BATCH_THRESHOLD", "200")]),
+        ).unwrap();
+
+        assert_eq!(config.threads, 4);
+        assert_eq!(config.batch.threshold, 200);
+        assert_eq!(config.batch.timeout_ms, 5);
+        assert_eq!(config.shutdown, Shutdown::default());
+
+        // --config wins over PUBSUB_CONFIG
+        let config = Config::load(
+            args(&["--config", file.path()]),
+            vars(&[("PUBSUB_CONFIG", "/nonexistent.toml")]),
+        ).unwrap();
+        assert_eq!(config.threads, 2);
+    }
+
+    #[test]
+    fn heartbeats_on_client_port_only() {
+        let config = Config::load(Vec::new(), Vec::new()).unwrap();
+        assert_eq!(config.publisher_options().idle_timeout, None);
+        assert_eq!(config.subscriber_options().idle_timeout, None);
+        assert_eq!(config.client_options().idle_timeout, Some(Duration::from_secs(90)));
+
+        let config = Config::load(args(&["--heartbeat-subscriber", "true"]), Vec::new()).unwrap();
+        assert_eq!(config.subscriber_options().heartbeat_interval, Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn none_limits() {
+        let file = ConfigFile::new("limits", r#"
+            [limits]
+            max_write_buffer = "none"
+            max_malformed_frames = 10
+        "#);
+
+        let config = Config::load(
+            args(&["--config", file.path(), "--heartbeat-idle-timeout-secs=None"]),
+            vars(&[("PUBSUB_LIMITS_BROADCAST_CAPACITY", "none"), ("PUBSUB_HEARTBEAT_INTERVAL_SECS", "none")]),
+        ).unwrap();
+
+        assert_eq!(config.limits.max_write_buffer, None);
+        assert_eq!(config.limits.max_malformed_frames, Some(10));
+        assert_eq!(config.limits.broadcast_capacity, None);
+        assert_eq!(config.heartbeat.interval_secs, None);
+        assert_eq!(config.heartbeat.idle_timeout_secs, None);
+
+        // Only limits can be turned off
+        let err = Config::load(args(&["--threads", "none"]), vars(&[])).unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue(..)));
+
+        let file = ConfigFile::new("bad-limit", r#"
+            [limits]
+            max_write_buffer = "off"
+        "#);
+        let err = Config::load(args(&["--config", file.path()]), vars(&[])).unwrap_err();
+        assert!(matches!(err, ConfigError::File(..)));
+    }
+
+    #[test]
+    fn validation_errors() {
+        let load = |flags: &[&str]| Config::load(args(flags), vars(&[]));
+
+        assert!(matches!(load(&["--threads", "0"]), Err(ConfigError::Invalid(_))));
+        assert!(matches!(load(&["--batch-threshold", "0"]), Err(ConfigError::Invalid(_))));
+        assert!(matches!(load(&["--listeners-client", "nowhere"]), Err(ConfigError::InvalidValue(..))));
+        assert!(matches!(load(&["--backpressure", "drop"]), Err(ConfigError::InvalidValue(..))));
+        assert!(matches!(load(&["--heartbeat-idle-timeout-secs", "10", "--heartbeat-interval-secs", "10"]), Err(ConfigError::Invalid(_))));
+        assert!(matches!(load(&["--threads"]), Err(ConfigError::MissingValue(_))));
+        assert!(matches!(load(&["--nope", "1"]), Err(ConfigError::UnknownKey(_))));
+        assert!(matches!(load(&["--help"]), Err(ConfigError::Help)));
+
+        let disabled = ["--features-publisher", "false", "--features-subscriber", "false", "--features-client", "false"];
+        assert!(matches!(load(&disabled), Err(ConfigError::Invalid(_))));
+
+        // A disabled listener's address isn't checked
+        assert!(load(&["--features-client", "false", "--listeners-client", "nowhere"]).is_ok());
+    }
+
+    #[test]
+    fn unknown_env() {
+        let env = vars(&[
+            ("PUBSUB_EMULATOR_HOST", "localhost:8085"),
+            ("PUBSUB_PROJECT_ID", "test"),
+            ("PUBSUB_THREADS", "2"),
+            ("HOME", "/root"),
+        ]);
+
+        let config = Config::load(args(&[]), env.clone()).unwrap();
+        assert_eq!(config.threads, 2);
+        assert_eq!(Config::unknown_env(env), vec!["PUBSUB_EMULATOR_HOST", "PUBSUB_PROJECT_ID"]);
+    }
+}
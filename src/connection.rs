@@ -1,35 +1,193 @@
 use bytes::{Bytes, BufMut, BytesMut};
+use iovec::IoVec;
 use serde::de::DeserializeOwned;
+use serde::Serialize;
 use sonr::net::tcp::ReactiveTcpStream;
 use sonr::reactor::{Reaction, Reactor};
-use std::io::{ErrorKind::WouldBlock, Read, Write};
+use std::collections::VecDeque;
+use std::io::{self, ErrorKind::{TimedOut, WouldBlock}, IoSlice, Read, Write};
+use std::net;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
-use crate::codec::LineCodec;
-use crate::BUFFER_SIZE;
+use crate::codec::{Codec, Decoded, DecodeError, EncodeError, LineCodec};
+use crate::metrics::METRICS;
+use crate::options::SlowConsumerPolicy;
+use crate::{BUFFER_SIZE, MAX_FRAME_LENGTH};
 
-pub struct Connection {
-    stream: ReactiveTcpStream,
+static CONNECTION_ID: AtomicU64 = AtomicU64::new(1);
+
+// Unique across threads, also used for in-process clients
+pub(crate) fn next_connection_id() -> u64 {
+    CONNECTION_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+// Maximum number of frames written in one `writev` call (well below `IOV_MAX`)
+const MAX_WRITE_FRAMES: usize = 64;
+
+/// The outcome of checking a connection for missed heartbeats.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Heartbeat {
+    /// Data was received recently enough
+    Alive,
+    /// Nothing received for a while: ping the peer
+    Ping,
+    /// Nothing received within the idle timeout,
+    /// the peer is assumed dead
+    Expired,
+}
+
+/// The outcome of queueing a delivery on a connection
+/// with a bounded write buffer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Delivery {
+    Queued,
+    /// The message was dropped, or older messages were
+    /// dropped to make room for it
+    Dropped,
+    /// The write buffer is full and the policy is to disconnect
+    Overflow,
+}
+
+// A frame in the write buffer.
+// Deliveries share the published batch rather than being copied.
+// Only deliveries can be dropped, never replies.
+struct Frame {
+    bytes: Bytes,
+    droppable: bool,
+}
+
+/// The stream a `Connection` reads from and writes to.
+/// Reactive streams are only read / written once the
+/// reactor has marked them as ready, blocking streams always are.
+pub trait Stream: Read + Write {
+    fn readable(&self) -> bool;
+    fn writable(&self) -> bool;
+
+    /// Write the frames with a single `writev`
+    fn write_frames(&mut self, frames: &[IoSlice]) -> io::Result<usize>;
+}
+
+impl Stream for ReactiveTcpStream {
+    fn readable(&self) -> bool {
+        ReactiveTcpStream::readable(self)
+    }
+
+    fn writable(&self) -> bool {
+        ReactiveTcpStream::writable(self)
+    }
+
+    // The stream wraps a mio 0.6 stream, which has no `write_vectored`:
+    // the default one only writes the first frame. mio's own
+    // `write_bufs` is an actual `writev`.
+    fn write_frames(&mut self, frames: &[IoSlice]) -> io::Result<usize> {
+        let first = match frames.first().and_then(|frame| IoVec::from_bytes(frame)) {
+            Some(first) => first,
+            None => return Ok(0),
+        };
+
+        let mut bufs = [first; MAX_WRITE_FRAMES];
+        let mut len = 0;
+        for (buf, frame) in bufs.iter_mut().zip(frames) {
+            match IoVec::from_bytes(frame) {
+                Some(frame) => *buf = frame,
+                None => break,
+            }
+            len += 1;
+        }
+
+        self.inner().write_bufs(&bufs[..len])
+    }
+}
+
+// Blocking, see `client::PubSubClient`
+impl Stream for net::TcpStream {
+    fn readable(&self) -> bool {
+        true
+    }
+
+    fn writable(&self) -> bool {
+        true
+    }
+
+    fn write_frames(&mut self, frames: &[IoSlice]) -> io::Result<usize> {
+        self.write_vectored(frames)
+    }
+}
+
+pub struct Connection<C: Codec = LineCodec, S: Stream = ReactiveTcpStream> {
+    // Unlike the stream's token this is unique across threads
+    id: u64,
+    stream: S,
     read_buffer: BytesMut,
-    write_buffer: BytesMut,
+    write_buffer: VecDeque<Frame>,
+    buffered: usize, // bytes in the write buffer
+    partial: bool, // the first frame has been partially written
+    codec: C,
+    malformed_frames: usize,
+    fatal_error: bool,
+    last_seen: Instant,
+    pinged: bool,
+    write_limit: Option<(usize, SlowConsumerPolicy)>,
+    paused: bool,
+    dropped: u64,
+    // Negotiated with a `Hello` (client port)
+    version: Option<u32>,
 }
 
-impl Connection {
-    pub fn new(stream: ReactiveTcpStream) -> Self {
+impl<C: Codec, S: Stream> Connection<C, S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_codec(stream, C::new(MAX_FRAME_LENGTH))
+    }
+
+    pub fn with_codec(stream: S, codec: C) -> Self {
         Self {
+            id: next_connection_id(),
             stream,
             read_buffer: BytesMut::with_capacity(BUFFER_SIZE),
-            write_buffer: BytesMut::with_capacity(BUFFER_SIZE),
+            write_buffer: VecDeque::new(),
+            buffered: 0,
+            partial: false,
+            codec,
+            malformed_frames: 0,
+            fatal_error: false,
+            last_seen: Instant::now(),
+            pinged: false,
+            write_limit: None,
+            paused: false,
+            dropped: 0,
+            version: None,
         }
     }
 
-    pub fn recv<T: DeserializeOwned>(&mut self) -> Option<Result<Vec<T>, ()>> {
+    /// Bound the deliveries queued for writing to `limit` bytes.
+    /// Replies are always queued.
+    pub fn set_write_limit(&mut self, limit: Option<usize>, policy: SlowConsumerPolicy) {
+        self.write_limit = limit.map(|limit| (limit, policy));
+    }
+
+    // Each complete frame is returned as a result,
+    // so malformed frames can be reported back to the peer.
+    pub fn recv<T: DeserializeOwned>(&mut self) -> Option<Result<Vec<Result<T, DecodeError>>, ()>> {
         if !self.stream.readable() {
             return None;
         }
 
+        // Once a fatal error is returned nothing more is read,
+        // as the connection is about to be closed.
+        if self.fatal_error {
+            return None;
+        }
+
+        // A frame can be larger than the buffer, so make sure
+        // there is always room to read more data.
+        if self.read_buffer.remaining_mut() == 0 {
+            self.read_buffer.reserve(BUFFER_SIZE);
+        }
+
         let res = {
-            let mut b = unsafe { self.read_buffer.bytes_mut() };
-            self.stream.read(&mut b)
+            let b = unsafe { self.read_buffer.bytes_mut() };
+            self.stream.read(b)
         };
 
         match res {
@@ -38,19 +196,35 @@ impl Connection {
 
             // Try to decode messages from the read data
             Ok(n) => {
+                // Any data counts as a heartbeat
+                self.last_seen = Instant::now();
+                self.pinged = false;
+
                 let buf_len = self.read_buffer.len() + n;
                 unsafe { self.read_buffer.set_len(buf_len); }
 
                 let mut v = Vec::new();
-                while let Some(val) = LineCodec::decode(&mut self.read_buffer) {
-                    v.push(val);
+                loop {
+                    match self.codec.decode(&mut self.read_buffer) {
+                        Decoded::Incomplete => break,
+                        Decoded::Frame(val) => v.push(Ok(val)),
+                        Decoded::Malformed(e) => {
+                            self.malformed_frames += 1;
+                            self.fatal_error = e.is_fatal();
+                            v.push(Err(e));
+                            if self.fatal_error {
+                                break;
+                            }
+                        }
+                    }
                 }
 
                 Some(Ok(v))
             }
             
             // Not an actual error
-            Err(ref e) if e.kind() == WouldBlock => None,
+            // (a blocking stream's read timeout is either)
+            Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut => None,
 
             // Connection closed. Ignoring the reason
             // for simplicity
@@ -58,6 +232,7 @@ impl Connection {
         }
     }
 
+    // The queued frames are written with a single vectored write
     pub fn write(&mut self) -> Option<Result<usize, ()>> {
         if !self.stream.writable() {
             return None
@@ -67,25 +242,377 @@ impl Connection {
             return None
         }
 
-        match self.stream.write(&self.write_buffer) {
+        let res = {
+            let mut frames = [IoSlice::new(&[]); MAX_WRITE_FRAMES];
+            let mut len = 0;
+            for (slice, frame) in frames.iter_mut().zip(&self.write_buffer) {
+                *slice = IoSlice::new(&frame.bytes);
+                len += 1;
+            }
+            self.stream.write_frames(&frames[..len])
+        };
+
+        match res {
             Ok(n) => {
-                self.write_buffer.split_to(n); // Remove sent data
+                self.advance(n); // Remove sent data
                 Some(Ok(n))
             }
-            Err(ref e) if e.kind() == WouldBlock => None,
+            Err(ref e) if e.kind() == WouldBlock || e.kind() == TimedOut => None,
             Err(_) => Some(Err(())),
         }
     }
 
+    // Remove `n` written bytes from the front of the write buffer
+    fn advance(&mut self, mut n: usize) {
+        self.buffered -= n;
+        while n > 0 {
+            let frame = match self.write_buffer.front_mut() {
+                Some(frame) => frame,
+                None => break,
+            };
+
+            if frame.bytes.len() > n {
+                frame.bytes.advance(n);
+                self.partial = true;
+                break;
+            }
+
+            n -= frame.bytes.len();
+            self.write_buffer.pop_front();
+            self.partial = false;
+        }
+    }
+
     pub fn add_payload(&mut self, payload: Bytes) {
-        if payload.len() > self.write_buffer.remaining_mut() {
-            self.write_buffer.reserve(payload.len());
+        self.push_frame(payload, false);
+    }
+
+    /// Queue a delivery, applying the slow consumer policy
+    /// if the write buffer is full.
+    pub fn deliver(&mut self, payload: Bytes) -> Delivery {
+        let (limit, policy) = match self.write_limit {
+            Some(write_limit) => write_limit,
+            None => {
+                self.push_frame(payload, true);
+                return Delivery::Queued;
+            }
+        };
+
+        if self.paused {
+            self.drop_message();
+            return Delivery::Dropped;
+        }
+
+        if self.buffered + payload.len() <= limit {
+            self.push_frame(payload, true);
+            return Delivery::Queued;
+        }
+
+        match policy {
+            // Counted by the reactor, which closes the connection
+            SlowConsumerPolicy::Disconnect => Delivery::Overflow,
+            SlowConsumerPolicy::DropNewest => {
+                self.drop_message();
+                Delivery::Dropped
+            }
+            SlowConsumerPolicy::Pause => {
+                METRICS.pauses.fetch_add(1, Ordering::Relaxed);
+                self.paused = true;
+                self.drop_message();
+                Delivery::Dropped
+            }
+            SlowConsumerPolicy::DropOldest => {
+                // Make room for more than just this message, so the
+                // buffer isn't rebuilt for every message under load.
+                self.drop_oldest(limit / 2);
+                if self.buffered + payload.len() <= limit {
+                    self.push_frame(payload, true);
+                } else {
+                    self.drop_message();
+                }
+                Delivery::Dropped
+            }
         }
-        self.write_buffer.put_slice(&payload);
     }
 
+    /// Once the write buffer has drained after dropping messages,
+    /// returns the number of messages dropped since the last call.
+    /// A paused connection resumes at this point.
+    pub fn recovered(&mut self) -> Option<(SlowConsumerPolicy, u64)> {
+        let (limit, policy) = self.write_limit?;
+        if self.dropped == 0 || self.buffered > limit / 2 {
+            return None;
+        }
+
+        self.paused = false;
+        Some((policy, std::mem::replace(&mut self.dropped, 0)))
+    }
+
+    fn push_frame(&mut self, payload: Bytes, droppable: bool) {
+        if payload.is_empty() {
+            return;
+        }
+        self.buffered += payload.len();
+        self.write_buffer.push_back(Frame { bytes: payload, droppable });
+    }
+
+    fn drop_message(&mut self) {
+        METRICS.dropped_messages.fetch_add(1, Ordering::Relaxed);
+        self.dropped += 1;
+    }
+
+    // Drop the oldest deliveries until the buffer is no larger than `target`.
+    // The first frame is kept if it has been partially written.
+    fn drop_oldest(&mut self, target: usize) {
+        let partial = if self.partial { 1 } else { 0 };
+        let mut buffered = self.buffered;
+        let mut dropped = 0;
+        let mut i = 0;
+        self.write_buffer.retain(|frame| {
+            let first = i < partial;
+            i += 1;
+            if first || !frame.droppable || buffered <= target {
+                return true;
+            }
+
+            buffered -= frame.bytes.len();
+            dropped += 1;
+            false
+        });
+
+        METRICS.dropped_messages.fetch_add(dropped, Ordering::Relaxed);
+        self.buffered = buffered;
+        self.dropped += dropped;
+    }
+
+    /// Everything queued has been written
+    pub fn is_flushed(&self) -> bool {
+        self.write_buffer.is_empty()
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Encode a message with the connection's codec and queue it for writing
+    pub fn send<T: Serialize>(&mut self, t: &T) -> Result<(), EncodeError> {
+        let payload = C::encode(t)?;
+        self.add_payload(payload);
+        Ok(())
+    }
+
+    /// The protocol version negotiated with a `Hello`, if any
+    pub fn version(&self) -> Option<u32> {
+        self.version
+    }
+
+    pub fn set_version(&mut self, version: u32) {
+        self.version = Some(version);
+    }
+
+    /// Number of malformed frames received on this connection
+    pub fn malformed_frames(&self) -> usize {
+        self.malformed_frames
+    }
+
+    /// Count as a heartbeat, for a connection that was left unread
+    pub fn seen(&mut self) {
+        self.last_seen = Instant::now();
+        self.pinged = false;
+    }
+
+    /// Check for missed heartbeats.
+    /// A connection is only pinged once per idle period.
+    pub fn heartbeat(&mut self, interval: Option<Duration>, idle_timeout: Option<Duration>) -> Heartbeat {
+        let idle = self.last_seen.elapsed();
+
+        if let Some(idle_timeout) = idle_timeout {
+            if idle >= idle_timeout {
+                return Heartbeat::Expired;
+            }
+        }
+
+        match interval {
+            Some(interval) if idle >= interval && !self.pinged => {
+                self.pinged = true;
+                Heartbeat::Ping
+            }
+            _ => Heartbeat::Alive,
+        }
+    }
+
+}
+
+impl<C: Codec> Connection<C, ReactiveTcpStream> {
     // Convenience, saving us from having to make the stream public
     pub fn react(&mut self, reaction: Reaction<()>) -> Reaction<()> {
         self.stream.react(reaction)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Accepts at most `capacity` bytes per write
+    struct TestStream {
+        written: Vec<u8>,
+        capacity: usize,
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(WouldBlock.into())
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.capacity);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for TestStream {
+        fn readable(&self) -> bool {
+            true
+        }
+
+        fn writable(&self) -> bool {
+            true
+        }
+
+        fn write_frames(&mut self, frames: &[IoSlice]) -> io::Result<usize> {
+            let mut written = 0;
+            for frame in frames {
+                let n = self.write(&frame[..frame.len().min(self.capacity - written)])?;
+                written += n;
+                if n < frame.len() {
+                    break;
+                }
+            }
+            Ok(written)
+        }
+    }
+
+    fn connection(limit: usize, policy: SlowConsumerPolicy) -> Connection<LineCodec, TestStream> {
+        let mut connection = Connection::new(TestStream { written: Vec::new(), capacity: usize::MAX });
+        connection.set_write_limit(Some(limit), policy);
+        connection
+    }
+
+    fn message(byte: u8) -> Bytes {
+        Bytes::from(vec![byte; 4])
+    }
+
+    fn queued(connection: &Connection<LineCodec, TestStream>) -> Vec<&[u8]> {
+        connection.write_buffer.iter().map(|frame| &frame.bytes[..]).collect()
+    }
+
+    #[test]
+    fn write_ends_mid_frame() {
+        let mut connection = connection(10, SlowConsumerPolicy::DropOldest);
+        connection.deliver(message(b'a'));
+        connection.deliver(message(b'b'));
+        connection.stream.capacity = 6;
+
+        assert_eq!(connection.write(), Some(Ok(6)));
+        assert_eq!(queued(&connection), vec![b"bb"]);
+        assert_eq!(connection.buffered, 2);
+        assert!(connection.partial);
+
+        // The partially written frame is never dropped
+        connection.drop_oldest(0);
+        assert_eq!(queued(&connection), vec![b"bb"]);
+
+        assert_eq!(connection.write(), Some(Ok(2)));
+        assert!(connection.is_flushed());
+        assert!(!connection.partial);
+        assert_eq!(connection.stream.written, b"aaaabbbb");
+    }
+
+    #[test]
+    fn write_ends_at_frame_boundary() {
+        let mut connection = connection(10, SlowConsumerPolicy::DropOldest);
+        connection.deliver(message(b'a'));
+        connection.deliver(message(b'b'));
+        connection.stream.capacity = 4;
+
+        assert_eq!(connection.write(), Some(Ok(4)));
+        assert_eq!(queued(&connection), vec![b"bbbb"]);
+        assert_eq!(connection.buffered, 4);
+        assert!(!connection.partial);
+
+        assert_eq!(connection.write(), Some(Ok(4)));
+        assert!(connection.is_flushed());
+        assert_eq!(connection.buffered, 0);
+        assert_eq!(connection.write(), None);
+    }
+
+    #[test]
+    fn disconnect() {
+        let mut connection = connection(10, SlowConsumerPolicy::Disconnect);
+        assert_eq!(connection.deliver(message(b'a')), Delivery::Queued);
+        assert_eq!(connection.deliver(message(b'b')), Delivery::Queued);
+        assert_eq!(connection.deliver(message(b'c')), Delivery::Overflow);
+
+        // Nothing was dropped
+        connection.write();
+        assert_eq!(connection.recovered(), None);
+        assert_eq!(connection.stream.written, b"aaaabbbb");
+    }
+
+    #[test]
+    fn drop_newest() {
+        let mut connection = connection(10, SlowConsumerPolicy::DropNewest);
+        connection.deliver(message(b'a'));
+        connection.deliver(message(b'b'));
+        assert_eq!(connection.deliver(message(b'c')), Delivery::Dropped);
+        assert_eq!(connection.recovered(), None);
+
+        connection.write();
+        assert_eq!(connection.recovered(), Some((SlowConsumerPolicy::DropNewest, 1)));
+        assert_eq!(connection.recovered(), None);
+        assert_eq!(connection.stream.written, b"aaaabbbb");
+    }
+
+    #[test]
+    fn drop_oldest() {
+        let mut connection = connection(10, SlowConsumerPolicy::DropOldest);
+        connection.add_payload(Bytes::from_static(b"ack"));
+        connection.deliver(message(b'a'));
+        assert_eq!(connection.deliver(message(b'b')), Delivery::Dropped);
+        assert_eq!(connection.recovered(), None);
+
+        // Replies are never dropped
+        connection.write();
+        assert_eq!(connection.recovered(), Some((SlowConsumerPolicy::DropOldest, 1)));
+        assert_eq!(connection.stream.written, b"ackbbbb");
+    }
+
+    #[test]
+    fn pause() {
+        let mut connection = connection(10, SlowConsumerPolicy::Pause);
+        connection.deliver(message(b'a'));
+        connection.deliver(message(b'b'));
+        assert_eq!(connection.deliver(message(b'c')), Delivery::Dropped);
+
+        // Dropped while paused, even though it would fit
+        connection.stream.capacity = 4;
+        connection.write();
+        assert_eq!(connection.deliver(message(b'd')), Delivery::Dropped);
+
+        // Resumed once drained to half the limit
+        assert_eq!(connection.recovered(), Some((SlowConsumerPolicy::Pause, 2)));
+        assert_eq!(connection.deliver(message(b'e')), Delivery::Queued);
+
+        connection.write();
+        connection.write();
+        assert_eq!(connection.stream.written, b"aaaabbbbeeee");
+    }
+}
@@ -0,0 +1,356 @@
+use std::collections::{HashMap, HashSet};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sonr::{Event, Token};
+use sonr::errors::Result;
+use sonr::reactor::Reaction;
+use sonr::net::tcp::{ReactiveTcpStream, TcpStream};
+
+use crate::codec::{Codec, DecodeError};
+use crate::connection::{Connection, Heartbeat};
+use crate::messages::{ErrorCode, ErrorMessage, Goodbye, Notice, Ping, ServerMessage, SlowConsumer};
+use crate::options::Options;
+use crate::shutdown::Drain;
+use crate::timer::{ReactiveTimerNotifier, TimerNotifier};
+
+/// What is left for the reactor to do with an event,
+/// see `Connections::react`
+pub enum Ready {
+    /// The broker is shutting down and reached a new stage,
+    /// see `is_draining` and `is_flushed`
+    Shutdown,
+    /// Read the connection
+    Read(Token),
+    /// Nothing, the event was handled
+    Handled,
+    /// Not an event for the connections
+    Other(Event),
+}
+
+/// Everything read from a connection in one go
+pub struct Received<T> {
+    /// Each frame as a result, so malformed frames
+    /// can be reported back to the peer
+    pub messages: Vec<std::result::Result<T, DecodeError>>,
+    /// The peer closed the connection after sending `messages`
+    pub closed: bool,
+}
+
+/// The connections of a reactor, and the handling every port shares:
+/// accepting, writing, heartbeats and draining on shutdown.
+/// Notices (pings, errors...) are sent the way the port expects them,
+/// see `Connections::tagged`.
+pub struct Connections<C: Codec> {
+    connections: HashMap<Token, Connection<C>>,
+    // Closed since the last call to `closed`
+    closed: Vec<Token>,
+    // Left unread, see `hold`
+    held: HashSet<Token>,
+    heartbeat: ReactiveTimerNotifier,
+    drain: Drain,
+    // The shutdown stage last reported, draining and flushed
+    stage: (bool, bool),
+    // A goodbye was sent, see `goodbye`
+    closing: bool,
+    options: Options,
+    tagged: bool,
+}
+
+impl<C: Codec> Connections<C> {
+    pub fn new(heartbeat: TimerNotifier, drain: Drain, options: Options) -> Result<Self> {
+        Ok(Self {
+            connections: HashMap::new(),
+            closed: Vec::new(),
+            held: HashSet::new(),
+            heartbeat: ReactiveTimerNotifier::new(heartbeat)?,
+            drain,
+            stage: (false, false),
+            closing: false,
+            options,
+            tagged: false,
+        })
+    }
+
+    /// Notices are sent as `ServerMessage`s (client port)
+    pub fn tagged(heartbeat: TimerNotifier, drain: Drain, options: Options) -> Result<Self> {
+        Ok(Self { tagged: true, ..Self::new(heartbeat, drain, options)? })
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.options
+    }
+
+    /// Connections accepted before the listener stopped are dropped
+    pub fn accept(&mut self, stream: TcpStream) {
+        if self.drain.is_draining() {
+            return
+        }
+
+        if let Ok(stream) = ReactiveTcpStream::new(stream) {
+            let token = stream.token();
+            let mut con = Connection::with_codec(stream, C::new(self.options.max_frame_length));
+            con.set_write_limit(self.options.max_write_buffer, self.options.slow_consumer_policy);
+            self.connections.insert(token, con);
+        }
+    }
+
+    /// Handle the shutdown signal, heartbeat ticks and connection events.
+    /// While shutting down connections are only written.
+    pub fn react(&mut self, event: Event) -> Ready {
+        // Shutting down:
+        if event.token() == self.drain.token() {
+            self.drain.receive();
+            return self.stage()
+        }
+
+        // Heartbeat timer tick:
+        if event.token() == self.heartbeat.token() {
+            let _ = self.heartbeat.try_recv();
+            if self.drain.is_draining() {
+                // The publishers may have run out of time
+                if let Ready::Shutdown = self.stage() {
+                    return Ready::Shutdown
+                }
+                self.drained();
+            } else {
+                self.heartbeat();
+            }
+            return Ready::Handled
+        }
+
+        // Connection event:
+        let token = event.token();
+        match self.connections.get_mut(&token) {
+            // Mark the underlying stream as readable / writable
+            Some(con) => { con.react(Reaction::Event(event)); }
+            None => return Ready::Other(event),
+        }
+
+        if self.drain.is_draining() {
+            self.write(token);
+            self.drained();
+            return Ready::Handled
+        }
+
+        if self.held.contains(&token) {
+            self.write(token);
+            return Ready::Handled
+        }
+
+        Ready::Read(token)
+    }
+
+    pub fn get(&self, token: Token) -> Option<&Connection<C>> {
+        self.connections.get(&token)
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut Connection<C>> {
+        self.connections.get_mut(&token)
+    }
+
+    pub fn tokens(&self) -> Vec<Token> {
+        self.connections.keys().cloned().collect()
+    }
+
+    /// Read everything available on the connection
+    pub fn recv<T: DeserializeOwned>(&mut self, token: Token) -> Received<T> {
+        let mut received = Received { messages: Vec::new(), closed: false };
+        if let Some(con) = self.connections.get_mut(&token) {
+            while let Some(messages) = con.recv::<T>() {
+                match messages {
+                    Ok(messages) => received.messages.extend(messages),
+                    Err(()) => {
+                        received.closed = true;
+                        break
+                    }
+                }
+            }
+        }
+        received
+    }
+
+    pub fn too_many_malformed_frames(&self, token: Token) -> bool {
+        self.connections
+            .get(&token)
+            .is_some_and(|con| self.options.too_many_malformed_frames(con.malformed_frames()))
+    }
+
+    /// Queue a reply, written on the next `write`
+    pub fn reply<T: Serialize>(&mut self, token: Token, reply: &T) {
+        if let Some(con) = self.connections.get_mut(&token) {
+            let _ = con.send(reply);
+        }
+    }
+
+    pub fn notify(&mut self, token: Token, notice: Notice) {
+        if self.tagged {
+            self.reply(token, &ServerMessage::from(notice));
+        } else {
+            self.reply(token, &notice);
+        }
+    }
+
+    /// Write any pending data to the connection.
+    /// If the write fails the connection is closed.
+    pub fn write(&mut self, token: Token) {
+        loop {
+            let con = match self.connections.get_mut(&token) {
+                Some(con) => con,
+                None => return,
+            };
+
+            while let Some(wrt_res) = con.write() {
+                if wrt_res.is_err() {
+                    self.close(token);
+                    return;
+                }
+            }
+
+            // Let a slow consumer know what it missed once it has caught up
+            match con.recovered() {
+                Some((policy, dropped)) => self.notify(token, Notice::SlowConsumer(SlowConsumer::new(policy, dropped))),
+                None => break,
+            }
+        }
+    }
+
+    /// Let the connection know why it is closed, then close it
+    pub fn disconnect(&mut self, token: Token, code: ErrorCode) {
+        self.notify(token, Notice::Error(ErrorMessage::new(code, "disconnecting")));
+        self.write(token);
+        self.close(token);
+    }
+
+    /// Stop reading the connection until it is released,
+    /// e.g. while its publishes are held back.
+    /// Its heartbeats are not checked meanwhile.
+    pub fn hold(&mut self, token: Token) {
+        if self.connections.contains_key(&token) {
+            self.held.insert(token);
+        }
+    }
+
+    /// Read the connection again, see `hold`
+    pub fn release(&mut self, token: Token) {
+        if self.held.remove(&token) {
+            if let Some(con) = self.connections.get_mut(&token) {
+                con.seen();
+            }
+        }
+    }
+
+    pub fn close(&mut self, token: Token) {
+        self.held.remove(&token);
+        if self.connections.remove(&token).is_some() {
+            self.closed.push(token);
+        }
+    }
+
+    /// The connections closed since the last call,
+    /// for the reactor to forget about
+    pub fn closed(&mut self) -> Vec<Token> {
+        std::mem::take(&mut self.closed)
+    }
+
+    /// Ping idle connections and disconnect the ones
+    /// that missed their heartbeats.
+    pub fn heartbeat(&mut self) {
+        let (interval, idle_timeout) = (self.options.heartbeat_interval, self.options.idle_timeout);
+        let mut ping = Vec::new();
+        let mut expired = Vec::new();
+        for (token, con) in self.connections.iter_mut() {
+            // Pongs are left unread along with everything else
+            if self.held.contains(token) {
+                continue;
+            }
+
+            match con.heartbeat(interval, idle_timeout) {
+                Heartbeat::Alive => {}
+                Heartbeat::Ping => ping.push(*token),
+                Heartbeat::Expired => expired.push(*token),
+            }
+        }
+
+        for token in ping {
+            self.notify(token, Notice::Ping(Ping { ping: true }));
+            self.write(token);
+        }
+
+        for token in expired {
+            self.disconnect(token, ErrorCode::IdleTimeout);
+        }
+    }
+
+    // Report a shutdown stage once, until the goodbye
+    fn stage(&mut self) -> Ready {
+        let stage = (self.drain.is_draining(), self.drain.is_flushed());
+        if self.closing || stage == self.stage {
+            return Ready::Handled
+        }
+
+        self.stage = stage;
+        Ready::Shutdown
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_draining()
+    }
+
+    /// Every publisher has flushed its batch, so what is
+    /// left to deliver can be sent before the goodbye
+    pub fn is_flushed(&self) -> bool {
+        self.drain.is_flushed()
+    }
+
+    /// A goodbye was sent, nothing more is delivered
+    pub fn is_closing(&self) -> bool {
+        self.closing
+    }
+
+    /// The reactor has published everything it buffered
+    pub fn flush(&mut self) {
+        self.drain.flush();
+    }
+
+    /// Send every connection a goodbye. Each one is closed
+    /// once everything queued for it has been written.
+    pub fn goodbye(&mut self) {
+        if self.closing {
+            return
+        }
+
+        self.closing = true;
+        for token in self.tokens() {
+            self.notify(token, Notice::Goodbye(Goodbye { goodbye: true }));
+            self.write(token);
+        }
+
+        self.drained();
+    }
+
+    /// Close the connections with nothing left to write,
+    /// or all of them once the deadline has passed.
+    /// The reactor is done draining once none are left.
+    /// Nothing is closed before the goodbye.
+    pub fn drained(&mut self) {
+        if !self.closing {
+            return
+        }
+
+        let expired = self.drain.is_expired();
+        let tokens = self.connections
+            .iter()
+            .filter(|(_, con)| expired || con.is_flushed())
+            .map(|(token, _)| *token)
+            .collect::<Vec<_>>();
+
+        for token in tokens {
+            self.close(token);
+        }
+
+        if self.connections.is_empty() {
+            self.drain.finish();
+        }
+    }
+}
@@ -1,8 +1,23 @@
+pub mod batch;
+pub mod broadcast;
+pub mod broker;
+pub mod client;
 pub mod codec;
+pub mod config;
 pub mod connection;
+pub mod connections;
+pub mod local;
 pub mod messages;
+pub mod metrics;
+pub mod options;
+pub mod peer;
 pub mod publisher;
+pub mod shutdown;
 pub mod subscriber;
+pub mod subscriptions;
 pub mod timer;
 
 const BUFFER_SIZE: usize = 1024 * 8;
+const MAX_FRAME_LENGTH: usize = 1024 * 1024;
+
+pub use broker::{Broker, BrokerBuilder, BrokerHandle};
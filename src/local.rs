@@ -0,0 +1,282 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use sonr::Token;
+use sonr::reactor::{Reactor, Reaction};
+use sonr::errors::Result;
+use sonr::sync::signal::{ReactiveSignalReceiver, SignalReceiver};
+
+use crate::broadcast::{local_size, BoundedBroadcast, Lag};
+use crate::connection::next_connection_id;
+use crate::messages::{AckMessage, Metadata, PubMessage};
+use crate::subscriptions::{normalize_pattern, Interest};
+
+/// Publish and subscribe from within the process, without a connection.
+///
+/// Messages go through the same broadcast and channel routing as the
+/// ones published over TCP, so both kinds of clients see each other's
+/// messages. Messages between in-process clients are never encoded.
+///
+/// ```no_run
+/// # use sonr::prelude::*;
+/// # use pubsub::local::LocalClient;
+/// # use pubsub::messages::PubMessage;
+/// # fn main() -> sonr::errors::Result<()> {
+/// # let broker = pubsub::Broker::builder().client("127.0.0.1:0").build()?;
+/// let client = broker.local_client();
+///
+/// // On a thread of its own (or as part of another reactor)
+/// let subscription = client.subscribe("orders.>").expect("valid pattern");
+/// std::thread::spawn(move || -> sonr::errors::Result<()> {
+///     System::init()?;
+///     let run = subscription.reactive()?.map(|message: PubMessage| {
+///         println!("{}: {:?}", message.channel, message.payload);
+///     });
+///     System::start(run)
+/// });
+///
+/// client.publish(PubMessage::new("orders.created", "hello"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LocalClient {
+    broadcast: BoundedBroadcast,
+    // Stands in for the connection id in the message metadata
+    id: u64,
+}
+
+impl LocalClient {
+    pub fn new(broadcast: BoundedBroadcast) -> Self {
+        Self {
+            broadcast,
+            id: next_connection_id(),
+        }
+    }
+
+    /// Publish a message right away (rather than batched).
+    /// Returns the same ack / nack as a publish over TCP.
+    pub fn publish(&self, mut message: PubMessage) -> AckMessage {
+        if self.broadcast.is_full() {
+            return AckMessage::nack(message.id, "broker overloaded");
+        }
+
+        message.metadata = Some(Metadata::new(self.id));
+        let id = message.id.clone();
+        match self.broadcast.publish_message(message) {
+            Ok(()) => AckMessage::ack(id),
+            Err(nack) => nack,
+        }
+    }
+
+    /// Subscribe to a channel pattern.
+    /// Returns `None` if the pattern is invalid.
+    pub fn subscribe(&self, pattern: &str) -> Option<LocalSubscription> {
+        let mut subscription = self.subscriber();
+        if !subscription.subscribe(pattern) {
+            return None;
+        }
+        Some(subscription)
+    }
+
+    /// A subscription to no channels yet
+    pub fn subscriber(&self) -> LocalSubscription {
+        let interest = Interest::default();
+        let (messages, lag) = self.broadcast.local_subscriber(interest.clone());
+
+        LocalSubscription {
+            messages,
+            patterns: Patterns { interest, patterns: HashSet::new() },
+            lag,
+        }
+    }
+}
+
+/// Messages published on the subscribed channels,
+/// received through `reactive`.
+/// Nothing more is published to it once dropped.
+pub struct LocalSubscription {
+    messages: SignalReceiver<PubMessage>,
+    patterns: Patterns,
+    // Publishers are held back while it is too far behind
+    lag: Arc<Lag>,
+}
+
+impl LocalSubscription {
+    /// Returns false if already subscribed or the pattern is invalid
+    pub fn subscribe(&mut self, pattern: &str) -> bool {
+        self.patterns.subscribe(pattern)
+    }
+
+    /// Messages already published on the channel may still be received.
+    /// Returns false if not subscribed.
+    pub fn unsubscribe(&mut self, pattern: &str) -> bool {
+        self.patterns.unsubscribe(pattern)
+    }
+
+    /// Receive the messages in a reactor.
+    /// Has to be called on the thread running the reactor.
+    pub fn reactive(self) -> Result<ReactiveLocalSubscription> {
+        Ok(ReactiveLocalSubscription {
+            messages: ReactiveSignalReceiver::new(self.messages)?,
+            patterns: self.patterns,
+            lag: self.lag,
+        })
+    }
+}
+
+/// A `LocalSubscription` as a reactor, outputting every message received
+pub struct ReactiveLocalSubscription {
+    messages: ReactiveSignalReceiver<PubMessage>,
+    patterns: Patterns,
+    lag: Arc<Lag>,
+}
+
+impl ReactiveLocalSubscription {
+    pub fn token(&self) -> Token {
+        self.messages.token()
+    }
+
+    /// See `LocalSubscription::subscribe`
+    pub fn subscribe(&mut self, pattern: &str) -> bool {
+        self.patterns.subscribe(pattern)
+    }
+
+    /// See `LocalSubscription::unsubscribe`
+    pub fn unsubscribe(&mut self, pattern: &str) -> bool {
+        self.patterns.unsubscribe(pattern)
+    }
+}
+
+impl Reactor for ReactiveLocalSubscription {
+    type Input = ();
+    type Output = PubMessage;
+
+    fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
+        match reaction {
+            Reaction::Event(event) if event.token() != self.messages.token() => Reaction::Event(event),
+            reaction => match self.messages.react(reaction) {
+                Reaction::Value(message) => {
+                    self.lag.received(local_size(&message));
+                    Reaction::Value(message)
+                }
+                reaction => reaction,
+            },
+        }
+    }
+}
+
+// The patterns of a subscription, kept in its `Interest`
+// so only matching messages are sent to it.
+struct Patterns {
+    interest: Interest,
+    patterns: HashSet<String>,
+}
+
+impl Patterns {
+    fn subscribe(&mut self, pattern: &str) -> bool {
+        match normalize_pattern(pattern) {
+            Some(pattern) if !self.patterns.contains(&pattern) => {
+                self.interest.add(&pattern);
+                self.patterns.insert(pattern);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn unsubscribe(&mut self, pattern: &str) -> bool {
+        match normalize_pattern(pattern) {
+            Some(pattern) if self.patterns.remove(&pattern) => {
+                self.interest.remove(&pattern);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::{Bytes, BytesMut};
+
+    use crate::batch::{put_message, Published};
+
+    #[test]
+    fn publish_to_local_subscribers() {
+        let client = LocalClient::new(BoundedBroadcast::unbounded());
+        let orders = client.subscribe("orders.>").unwrap();
+        let users = client.subscribe("users").unwrap();
+
+        let mut message = PubMessage::new("orders.eu.created", "hello");
+        message.id = Some("1".into());
+        let ack = client.publish(message);
+        assert!(ack.is_ack());
+        assert_eq!(ack.id(), Some("1"));
+
+        let received = orders.messages.try_recv().unwrap();
+        assert_eq!(received.channel, "orders.eu.created");
+        assert_eq!(&received.payload[..], b"hello");
+        assert_eq!(received.metadata.unwrap().publisher, client.id);
+        assert!(orders.messages.try_recv().is_err());
+        assert!(users.messages.try_recv().is_err());
+    }
+
+    #[test]
+    fn subscribe_and_unsubscribe() {
+        let client = LocalClient::new(BoundedBroadcast::unbounded());
+        assert!(client.subscribe("orders..eu").is_none());
+
+        let mut subscription = client.subscriber();
+        assert!(subscription.subscribe("orders"));
+        assert!(!subscription.subscribe("orders"));
+
+        client.publish(PubMessage::new("orders", "first"));
+        assert!(subscription.unsubscribe("orders"));
+        assert!(!subscription.unsubscribe("orders"));
+        client.publish(PubMessage::new("orders", "second"));
+
+        assert_eq!(&subscription.messages.try_recv().unwrap().payload[..], b"first");
+        assert!(subscription.messages.try_recv().is_err());
+    }
+
+    #[test]
+    fn shared_with_the_tcp_ports() {
+        let broadcast = BoundedBroadcast::unbounded();
+        let client = LocalClient::new(broadcast.clone());
+        let local = client.subscribe("orders").unwrap();
+        let (tcp, _lag, interest) = broadcast.subscriber().into_parts();
+        interest.add("orders");
+
+        // Published by an in-process client, framed for the subscriber port
+        client.publish(PubMessage::new("orders", "local"));
+        let mut batch = tcp.try_recv().unwrap();
+        let published = Published::split(&mut batch).unwrap();
+        assert_eq!(&published.message().unwrap().payload[..], b"local");
+        assert_eq!(&local.messages.try_recv().unwrap().payload[..], b"local");
+
+        // Published over TCP, decoded for in-process subscribers
+        let mut payload = BytesMut::new();
+        put_message(&mut payload, &PubMessage::new("orders", "tcp"), false).unwrap();
+        broadcast.publish(payload.freeze());
+        assert_eq!(&local.messages.try_recv().unwrap().payload[..], b"tcp");
+        assert!(tcp.try_recv().is_ok());
+    }
+
+    #[test]
+    fn nack_when_overloaded() {
+        let client = LocalClient::new(BoundedBroadcast::bounded(1));
+        let subscription = client.subscribe("orders").unwrap();
+
+        assert!(client.publish(PubMessage::new("orders", Bytes::from_static(b"a"))).is_ack());
+        let nack = client.publish(PubMessage::new("orders", "b"));
+        assert!(!nack.is_ack());
+        assert_eq!(nack.reason(), Some("broker overloaded"));
+
+        // Taking the message makes room again
+        let mut subscription = subscription.reactive().unwrap();
+        assert!(matches!(subscription.react(Reaction::Continue), Reaction::Value(_)));
+        assert!(client.publish(PubMessage::new("orders", "c")).is_ack());
+    }
+}
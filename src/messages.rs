@@ -1,23 +1,546 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 
+use crate::codec::DecodeError;
+use crate::options::SlowConsumerPolicy;
+
+/// Protocol versions understood by the client port
+pub const PROTOCOL_VERSIONS: &[u32] = &[1];
+
+/// Messages accepted on the client port, where a single connection
+/// can both publish and subscribe.
+/// Every message is tagged with an `op`: `{"op": "sub", "channel": "abc"}`.
+/// Unknown ops are answered with an `unknown_message` error rather than
+/// closing the connection, so new ops can be added without breaking existing clients.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ClientMessage {
+    Hello(Hello),
+    Ping,
+    /// Reply to a `Ping` sent by the broker
+    Pong,
+    #[serde(rename = "pub")]
+    Publish(PubMessage),
+    Confirm(Confirm),
+    #[serde(rename = "sub")]
+    Subscribe(Subscribe),
+    #[serde(rename = "unsub")]
+    Unsubscribe { channel: String },
+    #[serde(rename = "unsub_all")]
+    UnsubscribeAll,
+    #[serde(rename = "list")]
+    ListSubscriptions,
+}
+
+/// Messages sent on the client port, tagged the same way as `ClientMessage`:
+/// `{"op": "msg", "channel": "abc", "payload": "aGk="}`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum ServerMessage {
+    Hello(Hello),
+    /// Sent to idle connections, see `Options::heartbeat_interval`
+    Ping,
+    Pong,
+    /// A message delivered to a subscriber
+    #[serde(rename = "msg")]
+    Message(PubMessage),
+    Ack(AckMessage),
+    Subscribed(SubscribeAck),
+    Unsubscribed(UnsubscribeAck),
+    Subscriptions(SubscriptionList),
+    SlowConsumer(SlowConsumer),
+    Error(ErrorMessage),
+    /// The broker is shutting down, nothing more is sent
+    Goodbye,
+}
+
+impl From<AckMessage> for ServerMessage {
+    fn from(ack: AckMessage) -> Self {
+        ServerMessage::Ack(ack)
+    }
+}
+
+impl From<SlowConsumer> for ServerMessage {
+    fn from(notice: SlowConsumer) -> Self {
+        ServerMessage::SlowConsumer(notice)
+    }
+}
+
+impl From<ErrorMessage> for ServerMessage {
+    fn from(err: ErrorMessage) -> Self {
+        ServerMessage::Error(err)
+    }
+}
+
+impl From<SubscriberReply> for ServerMessage {
+    fn from(reply: SubscriberReply) -> Self {
+        match reply {
+            SubscriberReply::Subscribed(ack) => ServerMessage::Subscribed(ack),
+            SubscriberReply::Unsubscribed(ack) => ServerMessage::Unsubscribed(ack),
+            SubscriberReply::Subscriptions(list) => ServerMessage::Subscriptions(list),
+            SubscriberReply::Pong(_) => ServerMessage::Pong,
+            SubscriberReply::Error(err) => ServerMessage::Error(err),
+        }
+    }
+}
+
+/// Sent by the broker on its own rather than as a reply.
+/// On the client port each one is sent as the matching `ServerMessage`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Notice {
+    Ping(Ping),
+    SlowConsumer(SlowConsumer),
+    Error(ErrorMessage),
+    Goodbye(Goodbye),
+}
+
+impl From<Notice> for ServerMessage {
+    fn from(notice: Notice) -> Self {
+        match notice {
+            Notice::Ping(_) => ServerMessage::Ping,
+            Notice::SlowConsumer(notice) => ServerMessage::SlowConsumer(notice),
+            Notice::Error(err) => ServerMessage::Error(err),
+            Notice::Goodbye(_) => ServerMessage::Goodbye,
+        }
+    }
+}
+
+/// `{"op": "hello", "versions": [1]}`
+/// Sent by the client with the protocol versions it supports.
+/// The broker replies with a `Hello` holding only the
+/// negotiated version, or an `unsupported_version` error.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Hello {
+    pub versions: Vec<u32>,
+}
+
+impl Hello {
+    pub fn new(versions: Vec<u32>) -> Self {
+        Self { versions }
+    }
+
+    /// The highest version supported by both sides
+    pub fn negotiate(&self) -> Option<u32> {
+        self.versions.iter().filter(|v| PROTOCOL_VERSIONS.contains(v)).max().cloned()
+    }
+}
+
+/// Messages accepted on the publisher port.
+/// Unlike the client port this port stays untagged, as existing
+/// publishers send bare `PubMessage`s. Anything unrecognized is an
+/// `Invalid` publish, so new commands are added to the client port
+/// (`ClientMessage`) only.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum PublishRequest {
+    Publish(PubMessage),
+    Confirm(Confirm),
+    /// Answered with a `Pong` rather than an ack
+    Ping(Ping),
+    /// Not answered
+    Pong(Pong),
+    /// Anything that is not a valid `PubMessage`,
+    /// keeping the id so the nack can be correlated.
+    Invalid(InvalidPublish),
+}
+
+/// `{"confirm": true}`
+/// In confirm mode the ack for a publish message is only sent once
+/// the message has been handed over for delivery, rather than
+/// as soon as it is received.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Confirm {
+    pub confirm: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+/// `{"ping": true}`
+/// Sent by a client to check the connection, or by the broker
+/// to idle clients. The other side answers with a `Pong`.
+/// Any data received counts as a heartbeat, not just a `Pong`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Ping {
+    pub ping: bool,
+}
+
+/// `{"pong": true}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Pong {
+    pub pong: bool,
+}
+
+/// `{"goodbye": true}`
+/// The last frame sent before the broker closes the connection
+/// when shutting down.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Goodbye {
+    pub goodbye: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct InvalidPublish {
+    #[serde(default)]
+    pub id: Option<String>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct PubMessage {
+    /// Chosen by the publisher and returned in the ack / nack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
     pub channel: String,
-    pub payload: String,
+    /// Base64 encoded in human readable formats (JSON),
+    /// raw bytes in binary formats.
+    #[serde(with = "payload")]
+    pub payload: Bytes,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// Arbitrary key / value pairs set by the publisher (trace ids etc.),
+    /// delivered to subscribers unchanged.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub headers: HashMap<String, String>,
+    /// Set by the broker when the message is received.
+    /// Anything sent by the publisher is replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
 }
 
+impl PubMessage {
+    pub fn new(channel: impl Into<String>, payload: impl Into<Bytes>) -> Self {
+        Self {
+            id: None,
+            channel: channel.into(),
+            payload: payload.into(),
+            content_type: None,
+            headers: HashMap::new(),
+            metadata: None,
+        }
+    }
+}
+
+static MESSAGE_SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Metadata {
+    /// Unique message id
+    pub id: String,
+    /// When the broker received the message,
+    /// in milliseconds since the unix epoch
+    pub received_at: u64,
+    /// Id of the publisher connection the message was received on
+    pub publisher: u64,
+}
+
+impl Metadata {
+    pub fn new(publisher: u64) -> Self {
+        let received_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+
+        // The sequence is unique within the process, and the
+        // timestamp keeps it unique across restarts.
+        let sequence = MESSAGE_SEQUENCE.fetch_add(1, Ordering::Relaxed);
+
+        Self {
+            id: format!("{:x}-{:x}", received_at, sequence),
+            received_at,
+            publisher,
+        }
+    }
+}
+
+mod payload {
+    use std::fmt;
+
+    use bytes::Bytes;
+    use serde::de::{self, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(payload: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&base64::encode(payload))
+        } else {
+            serializer.serialize_bytes(payload)
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        let human_readable = deserializer.is_human_readable();
+        deserializer.deserialize_any(PayloadVisitor { human_readable })
+    }
+
+    struct PayloadVisitor {
+        human_readable: bool,
+    }
+
+    impl<'de> Visitor<'de> for PayloadVisitor {
+        type Value = Bytes;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a base64 encoded string or bytes")
+        }
+
+        // Strings are base64 in human readable formats,
+        // anywhere else they are taken as is.
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Bytes, E> {
+            if self.human_readable {
+                base64::decode(v).map(Bytes::from).map_err(E::custom)
+            } else {
+                Ok(Bytes::from(v.as_bytes()))
+            }
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+            Ok(Bytes::from(v))
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+            Ok(Bytes::from(v))
+        }
+    }
+}
+
+/// Reply to every message received on the publisher port.
+/// `{"ack": true, "id": "1"}` or `{"ack": false, "id": "1", "reason": "..."}`
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct AckMessage {
     ack: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
 }
 
 impl AckMessage {
-    pub fn new() -> Self {
-        Self { ack: true }
+    pub fn ack(id: Option<String>) -> Self {
+        Self { ack: true, id, reason: None }
+    }
+
+    /// Negative acknowledgement, sent when a
+    /// publish message could not be handled.
+    pub fn nack(id: Option<String>, reason: impl Into<String>) -> Self {
+        Self { ack: false, id, reason: Some(reason.into()) }
+    }
+
+    pub fn is_ack(&self) -> bool {
+        self.ack
+    }
+
+    /// The id of the publish message this is a reply to
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+
+    pub fn reason(&self) -> Option<&str> {
+        self.reason.as_deref()
     }
 }
 
+/// Messages accepted on the subscriber port.
+/// Each message is told apart by its field name, so
+/// `{"channel": "abc"}` is still a valid subscribe message.
+/// Like `PublishRequest` this stays untagged for existing subscribers,
+/// new commands are added to `ClientMessage` only.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SubscriberMessage {
+    Subscribe(Subscribe),
+    Unsubscribe(Unsubscribe),
+    UnsubscribeAll(UnsubscribeAll),
+    ListSubscriptions(ListSubscriptions),
+    Ping(Ping),
+    Pong(Pong),
+}
+
+/// Reply to a `SubscriberMessage`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum SubscriberReply {
+    Subscribed(SubscribeAck),
+    Unsubscribed(UnsubscribeAck),
+    Subscriptions(SubscriptionList),
+    Pong(Pong),
+    Error(ErrorMessage),
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct Subscribe {
     pub channel: String,
 }
+
+/// Sent to a subscriber once a `Subscribe` has taken effect.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SubscribeAck {
+    pub subscribed: String,
+}
+
+impl SubscribeAck {
+    pub fn new(subscribed: String) -> Self {
+        Self { subscribed }
+    }
+}
+
+/// `{"unsubscribe": "abc"}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Unsubscribe {
+    #[serde(rename = "unsubscribe")]
+    pub channel: String,
+}
+
+/// `{"unsubscribe_all": true}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UnsubscribeAll {
+    pub unsubscribe_all: bool,
+}
+
+/// Sent to a subscriber once an `Unsubscribe` or `UnsubscribeAll`
+/// has been handled, listing the channels that were left.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct UnsubscribeAck {
+    pub unsubscribed: Vec<String>,
+}
+
+impl UnsubscribeAck {
+    pub fn new(unsubscribed: Vec<String>) -> Self {
+        Self { unsubscribed }
+    }
+}
+
+/// `{"list_subscriptions": true}`
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ListSubscriptions {
+    pub list_subscriptions: bool,
+}
+
+/// Reply to `ListSubscriptions`.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SubscriptionList {
+    pub subscriptions: Vec<String>,
+}
+
+impl SubscriptionList {
+    pub fn new(subscriptions: Vec<String>) -> Self {
+        Self { subscriptions }
+    }
+}
+
+/// `{"slow_consumer": "drop_oldest", "dropped": 10}`
+/// Sent to a subscriber that fell behind once it has caught up,
+/// with the number of messages it missed.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct SlowConsumer {
+    pub slow_consumer: SlowConsumerPolicy,
+    pub dropped: u64,
+}
+
+impl SlowConsumer {
+    pub fn new(policy: SlowConsumerPolicy, dropped: u64) -> Self {
+        Self { slow_consumer: policy, dropped }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame could not be decoded
+    InvalidFrame,
+    /// Valid JSON, but not a message the port understands
+    UnknownMessage,
+    /// The channel is not a valid channel or pattern
+    InvalidChannel,
+    /// The client sent too many malformed frames and is disconnected
+    TooManyMalformedFrames,
+    /// The frame exceeds the maximum frame length and the client is disconnected
+    FrameTooLong,
+    /// None of the protocol versions in a `Hello` are supported
+    UnsupportedVersion,
+    /// Nothing was received within the idle timeout and the client is disconnected
+    IdleTimeout,
+    /// The client is not reading its messages fast enough and is disconnected
+    SlowConsumer,
+}
+
+/// Sent when a message could not be handled.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ErrorMessage {
+    pub code: ErrorCode,
+    pub reason: String,
+}
+
+impl ErrorMessage {
+    pub fn new(code: ErrorCode, reason: impl Into<String>) -> Self {
+        Self { code, reason: reason.into() }
+    }
+}
+
+impl From<&DecodeError> for ErrorMessage {
+    fn from(err: &DecodeError) -> Self {
+        let code = match err {
+            DecodeError::Syntax(_) => ErrorCode::InvalidFrame,
+            DecodeError::Data(_) => ErrorCode::UnknownMessage,
+            DecodeError::FrameTooLong(_) => ErrorCode::FrameTooLong,
+        };
+        Self::new(code, err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hello_negotiates_highest_common_version() {
+        assert_eq!(Hello::new(vec![0, 1, 9]).negotiate(), Some(1));
+        assert_eq!(Hello::new(vec![1]).negotiate(), Some(1));
+    }
+
+    #[test]
+    fn hello_without_common_version() {
+        assert_eq!(Hello::new(vec![0, 9]).negotiate(), None);
+        assert_eq!(Hello::new(Vec::new()).negotiate(), None);
+    }
+
+    #[test]
+    fn op_tags() {
+        let messages = [
+            (r#"{"op":"hello","versions":[1]}"#, "hello"),
+            (r#"{"op":"sub","channel":"orders.*"}"#, "sub"),
+            (r#"{"op":"unsub","channel":"orders.*"}"#, "unsub"),
+            (r#"{"op":"unsub_all"}"#, "unsub_all"),
+            (r#"{"op":"list"}"#, "list"),
+            (r#"{"op":"pub","channel":"orders","payload":"aGk="}"#, "pub"),
+        ];
+
+        for (json, op) in messages.iter() {
+            let message: ClientMessage = serde_json::from_str(json).unwrap();
+            let value = serde_json::to_value(&message).unwrap();
+            assert_eq!(value["op"], *op);
+            assert_eq!(value, serde_json::from_str::<serde_json::Value>(json).unwrap());
+        }
+
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"op":"unknown"}"#).is_err());
+        assert!(serde_json::from_str::<ClientMessage>(r#"{"channel":"orders"}"#).is_err());
+    }
+
+    #[test]
+    fn server_op_tags() {
+        let message = ServerMessage::Message(PubMessage::new("orders", "hi"));
+        let json = serde_json::to_string(&message).unwrap();
+        assert!(json.starts_with(r#"{"op":"msg","channel":"orders""#));
+
+        match serde_json::from_str(&json).unwrap() {
+            ServerMessage::Message(message) => assert_eq!(&message.payload[..], b"hi"),
+            message => panic!("unexpected message: {:?}", message),
+        }
+
+        let hello = serde_json::to_string(&ServerMessage::Hello(Hello::new(vec![1]))).unwrap();
+        assert_eq!(hello, r#"{"op":"hello","versions":[1]}"#);
+    }
+}
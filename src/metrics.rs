@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::Serialize;
+
+/// Process wide counters, shared by every worker thread.
+pub static METRICS: Metrics = Metrics {
+    dropped_messages: AtomicU64::new(0),
+    pauses: AtomicU64::new(0),
+    slow_consumer_disconnects: AtomicU64::new(0),
+};
+
+pub struct Metrics {
+    /// Deliveries dropped because of a full write buffer
+    pub dropped_messages: AtomicU64,
+    /// Number of times a connection was paused because of a full write buffer.
+    /// Not decremented on resume, so this is not the number currently paused.
+    pub pauses: AtomicU64,
+    /// Connections closed because of a full write buffer
+    pub slow_consumer_disconnects: AtomicU64,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            dropped_messages: self.dropped_messages.load(Ordering::Relaxed),
+            pauses: self.pauses.load(Ordering::Relaxed),
+            slow_consumer_disconnects: self.slow_consumer_disconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The value of every counter at a point in time
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct MetricsSnapshot {
+    pub dropped_messages: u64,
+    pub pauses: u64,
+    pub slow_consumer_disconnects: u64,
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::MAX_FRAME_LENGTH;
+
+/// What a publisher does while the broadcast is full.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Backpressure {
+    /// Hold back the publishes until there is room, leaving
+    /// anything sent after them in the socket buffers.
+    /// Other messages received with them are still handled.
+    Pause,
+    /// Keep reading but nack every publish
+    Nack,
+}
+
+/// What to do with a subscriber whose write buffer is full,
+/// i.e. it is not reading its messages fast enough.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Close the connection
+    Disconnect,
+    /// Drop queued messages to make room for new ones
+    DropOldest,
+    /// Drop new messages until there is room
+    DropNewest,
+    /// `DropNewest` with hysteresis: once full, new messages are
+    /// dropped until the buffer has drained to half the limit, even
+    /// if they would fit. Nothing is held back for later delivery,
+    /// the subscriber gets a gap rather than every other message.
+    Pause,
+}
+
+/// Options shared by the publisher, subscriber and client reactors.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Frames longer than this (in bytes) are rejected
+    /// and the client is disconnected.
+    pub max_frame_length: usize,
+
+    /// Disconnect a client once it has sent more than this
+    /// many malformed frames. `None` never disconnects.
+    pub max_malformed_frames: Option<usize>,
+
+    /// Ping clients that have not sent anything for this long.
+    /// Checked on every tick of the heartbeat timer.
+    pub heartbeat_interval: Option<Duration>,
+
+    /// Disconnect clients that have not sent anything
+    /// (including a pong) for this long. `None` never disconnects.
+    pub idle_timeout: Option<Duration>,
+
+    /// Maximum number of bytes of deliveries waiting to be written
+    /// to a subscriber. `None` is unbounded.
+    pub max_write_buffer: Option<usize>,
+
+    /// Applied once `max_write_buffer` is reached.
+    /// Dropped messages are reported to the client once it catches up.
+    pub slow_consumer_policy: SlowConsumerPolicy,
+
+    /// Applied by publishers while the broadcast is full,
+    /// see `BoundedBroadcast::bounded`.
+    pub backpressure: Backpressure,
+}
+
+impl Options {
+    pub fn too_many_malformed_frames(&self, count: usize) -> bool {
+        match self.max_malformed_frames {
+            Some(max) => count > max,
+            None => false,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            max_frame_length: MAX_FRAME_LENGTH,
+            max_malformed_frames: None,
+            heartbeat_interval: None,
+            idle_timeout: None,
+            max_write_buffer: None,
+            slow_consumer_policy: SlowConsumerPolicy::Disconnect,
+            backpressure: Backpressure::Pause,
+        }
+    }
+}
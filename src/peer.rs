@@ -0,0 +1,301 @@
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
+
+use sonr::{Event, Token};
+use sonr::reactor::{Reactor, Reaction};
+use sonr::errors::Result;
+use sonr::net::tcp::TcpStream;
+
+use crate::batch::{BatchReceiver, PublishBatch};
+use crate::broadcast::BoundedBroadcast;
+use crate::connection::Delivery;
+use crate::connections::{Connections, Ready};
+use crate::codec::Codec;
+use crate::messages::{
+    ClientMessage, ErrorCode, ErrorMessage, Hello, ListSubscriptions, ServerMessage,
+    SubscriberMessage, Unsubscribe, UnsubscribeAll, PROTOCOL_VERSIONS,
+};
+use crate::metrics::METRICS;
+use crate::options::Options;
+use crate::shutdown::Drain;
+use crate::subscriber::handle_subscription;
+use crate::subscriptions::Subscriptions;
+use crate::timer::{TimerNotifier, ReactiveTimerNotifier};
+
+/// Connections that can both publish and subscribe.
+/// Combines the `Publisher` and the `Subscriber`:
+/// published messages are batched onto the broadcast, and
+/// messages received from the broadcast are delivered to
+/// the subscribed connections (including the publishing one).
+pub struct Peer<C: Codec> {
+    connections: Connections<C>,
+    subscriptions: Subscriptions,
+    batch: PublishBatch,
+    messages: BatchReceiver,
+    timer: ReactiveTimerNotifier,
+}
+
+impl<C: Codec> Peer<C> {
+    pub fn new(
+        broadcast: BoundedBroadcast,
+        buffer_threshold: usize,
+        timer: TimerNotifier,
+        heartbeat: TimerNotifier,
+        drain: Drain,
+        options: Options,
+    ) -> Result<Self> {
+        let messages = BatchReceiver::new(broadcast.subscriber())?;
+        let backpressure = options.backpressure;
+        Ok(Self {
+            connections: Connections::tagged(heartbeat, drain, options)?,
+            subscriptions: Subscriptions::with_interest(messages.interest()),
+            messages,
+            batch: PublishBatch::new(broadcast, buffer_threshold, backpressure),
+            timer: ReactiveTimerNotifier::new(timer)?,
+        })
+    }
+
+    // Publish the buffered messages and send any
+    // acks that were waiting for them to be published.
+    fn publish(&mut self) {
+        let mut connection_ids = HashSet::new();
+        for (connection_id, ack) in self.batch.publish() {
+            self.reply(connection_id, ack);
+            connection_ids.insert(connection_id);
+        }
+
+        for connection_id in connection_ids {
+            self.connections.write(connection_id);
+        }
+    }
+
+    // Deliver the messages received from the broadcast
+    // to the subscribed connections.
+    fn deliver(&mut self) {
+        let mut connection_ids = HashSet::new();
+        let mut overflowed = HashSet::new();
+        while let Some(message) = self.messages.next_message() {
+            let subscribers = self.subscriptions.subscribers(message.channel());
+            if subscribers.is_empty() {
+                continue;
+            }
+
+            if let Some(encoded_message) = message.encode_tagged::<C>() {
+                for &cid in subscribers {
+                    // Closed below, once every message has been handled
+                    if overflowed.contains(&cid) {
+                        continue;
+                    }
+
+                    if let Some(con) = self.connections.get_mut(cid) {
+                        match con.deliver(encoded_message.clone()) {
+                            Delivery::Overflow => overflowed.insert(cid),
+                            _ => connection_ids.insert(cid),
+                        };
+                    }
+                }
+            }
+        }
+
+        for connection_id in overflowed {
+            connection_ids.remove(&connection_id);
+            self.connections.disconnect(connection_id, ErrorCode::SlowConsumer);
+            METRICS.slow_consumer_disconnects.fetch_add(1, Ordering::Relaxed);
+        }
+
+        for connection_id in connection_ids {
+            self.connections.write(connection_id);
+        }
+    }
+
+    fn handle_message(&mut self, connection_id: Token, message: ClientMessage) {
+        let message = match message {
+            ClientMessage::Hello(hello) => {
+                match hello.negotiate() {
+                    Some(version) => {
+                        if let Some(con) = self.connections.get_mut(connection_id) {
+                            con.set_version(version);
+                        }
+                        self.reply(connection_id, ServerMessage::Hello(Hello::new(vec![version])));
+                    }
+                    None => {
+                        let reason = format!("supported versions: {:?}", PROTOCOL_VERSIONS);
+                        self.reply(connection_id, ErrorMessage::new(ErrorCode::UnsupportedVersion, reason));
+                    }
+                }
+                return
+            }
+            ClientMessage::Ping => {
+                self.reply(connection_id, ServerMessage::Pong);
+                return
+            }
+            ClientMessage::Pong => return,
+            ClientMessage::Publish(message) => {
+                let publisher = match self.connections.get(connection_id) {
+                    Some(con) => con.id(),
+                    None => return,
+                };
+
+                if let Some(ack) = self.batch.push(connection_id, publisher, message) {
+                    self.reply(connection_id, ack);
+                }
+                return
+            }
+            ClientMessage::Confirm(confirm) => {
+                if let Some(ack) = self.batch.confirm(connection_id, confirm) {
+                    self.reply(connection_id, ack);
+                }
+                return
+            }
+            ClientMessage::Subscribe(subscribe) => SubscriberMessage::Subscribe(subscribe),
+            ClientMessage::Unsubscribe { channel } => SubscriberMessage::Unsubscribe(Unsubscribe { channel }),
+            ClientMessage::UnsubscribeAll => SubscriberMessage::UnsubscribeAll(UnsubscribeAll { unsubscribe_all: true }),
+            ClientMessage::ListSubscriptions => SubscriberMessage::ListSubscriptions(ListSubscriptions { list_subscriptions: true }),
+        };
+
+        if let Some(reply) = handle_subscription(&mut self.subscriptions, connection_id, message) {
+            self.reply(connection_id, reply);
+        }
+    }
+
+    fn read(&mut self, connection_id: Token) {
+        let received = self.connections.recv::<ClientMessage>(connection_id);
+        let mut fatal = false;
+        for message in received.messages {
+            match message {
+                Ok(message) => self.handle_message(connection_id, message),
+                Err(e) => {
+                    fatal |= e.is_fatal();
+                    self.reply(connection_id, ErrorMessage::from(&e));
+                }
+            }
+        }
+
+        // Messages received before the connection closed are still published
+        if received.closed {
+            self.connections.close(connection_id);
+            self.publish();
+            return
+        }
+
+        // Disconnect clients sending too much garbage,
+        // after letting them know why.
+        if self.connections.too_many_malformed_frames(connection_id) {
+            self.connections.disconnect(connection_id, ErrorCode::TooManyMalformedFrames);
+            return
+        }
+
+        if fatal {
+            self.connections.write(connection_id);
+            self.connections.close(connection_id);
+            return
+        }
+
+        // Only the publishes are held back while the broadcast is full,
+        // see `Publisher::read`
+        if self.batch.is_held(connection_id) {
+            self.connections.hold(connection_id);
+        }
+
+        if self.batch.is_full() {
+            self.publish();
+        }
+
+        // Write replies
+        self.connections.write(connection_id);
+    }
+
+    // Add the publishes held back while the broadcast
+    // was full, once there is room again, and read
+    // their connections again.
+    fn resume(&mut self) {
+        for connection_id in self.batch.resume() {
+            self.connections.release(connection_id);
+            self.read(connection_id);
+        }
+    }
+
+    fn reply(&mut self, connection_id: Token, reply: impl Into<ServerMessage>) {
+        self.connections.reply(connection_id, &reply.into());
+    }
+
+    // Called at each stage of the shutdown. Once draining nothing is
+    // read and the buffered batch is published right away, messages
+    // keep being delivered as they arrive. Once every publisher has
+    // flushed, the rest of the broadcast is delivered and every
+    // connection sent a goodbye. They close once everything is
+    // written, or when the deadline passes.
+    fn shutdown(&mut self) {
+        self.publish();
+        self.connections.flush();
+        if !self.connections.is_flushed() {
+            return
+        }
+
+        if self.messages.receive(Reaction::Continue) {
+            self.deliver();
+        }
+
+        self.connections.goodbye();
+    }
+
+    fn react_to_event(&mut self, event: Event) -> Reaction<()> {
+        let event = match self.connections.react(event) {
+            Ready::Shutdown => {
+                self.shutdown();
+                return Reaction::Continue
+            }
+            Ready::Read(connection_id) => {
+                self.read(connection_id);
+                return Reaction::Continue
+            }
+            Ready::Handled => return Reaction::Continue,
+            Ready::Other(event) => event,
+        };
+
+        // Timer tick event:
+        if event.token() == self.timer.token() {
+            let _ = self.timer.try_recv();
+            if self.connections.is_draining() {
+                self.connections.drained();
+            } else {
+                self.resume();
+                self.publish();
+            }
+            return Reaction::Continue
+        }
+
+        // Incoming messages:
+        if event.token() == self.messages.token() {
+            if !self.connections.is_closing() && self.messages.receive(event.into()) {
+                self.deliver();
+            }
+            return Reaction::Continue
+        }
+
+        event.into()
+    }
+}
+
+impl<C: Codec> Reactor for Peer<C> {
+    type Input = TcpStream;
+    type Output = ();
+
+    fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
+        let reaction = match reaction {
+            Reaction::Value(stream) => {
+                self.connections.accept(stream);
+                Reaction::Continue
+            }
+            Reaction::Event(event) => self.react_to_event(event),
+            Reaction::Continue => Reaction::Continue,
+        };
+
+        for connection_id in self.connections.closed() {
+            self.subscriptions.unsubscribe_all(connection_id);
+            self.batch.remove(connection_id);
+        }
+
+        reaction
+    }
+}
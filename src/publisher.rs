@@ -1,120 +1,208 @@
-use std::collections::HashMap;
-
 use sonr::reactor::{Reactor, Reaction};
-use sonr::sync::broadcast::Broadcast;
-use sonr::net::tcp::{ReactiveTcpStream, TcpStream};
-use sonr::Token;
+use sonr::net::tcp::TcpStream;
+use sonr::{Event, Token};
 use sonr::errors::Result;
-use bytes::{Bytes, BytesMut, BufMut};
 
-use crate::connection::Connection;
-use crate::codec::LineCodec;
-use crate::messages::{PubMessage, AckMessage};
+use crate::batch::PublishBatch;
+use crate::broadcast::BoundedBroadcast;
+use crate::codec::Codec;
+use crate::connections::{Connections, Ready};
+use crate::messages::{PublishRequest, AckMessage, Pong};
+use crate::options::Options;
+use crate::shutdown::Drain;
 use crate::timer::{TimerNotifier, ReactiveTimerNotifier};
 
 
-pub struct Publisher {
-    connections: HashMap<Token, Connection>,
-    broadcast: Broadcast<Bytes>,
-    buffer_threshold: usize, // buffer messages
-    publish_payload: BytesMut,
+pub struct Publisher<C: Codec> {
+    connections: Connections<C>,
+    batch: PublishBatch,
     timer: ReactiveTimerNotifier,
 }
 
-impl Publisher {
-    pub fn new(broadcast: Broadcast<Bytes>, buffer_threshold: usize, timer: TimerNotifier) -> Result<Self> {
+impl<C: Codec> Publisher<C> {
+    pub fn new(
+        broadcast: BoundedBroadcast,
+        buffer_threshold: usize,
+        timer: TimerNotifier,
+        heartbeat: TimerNotifier,
+        drain: Drain,
+        options: Options,
+    ) -> Result<Self> {
         let timer = ReactiveTimerNotifier::new(timer)?;
+        let backpressure = options.backpressure;
 
         Ok(Self {  
-            connections: HashMap::new(),
-            broadcast,
-            buffer_threshold,
-            publish_payload: BytesMut::with_capacity(buffer_threshold * 2),
+            connections: Connections::new(heartbeat, drain, options)?,
+            batch: PublishBatch::new(broadcast, buffer_threshold, backpressure),
             timer,
         })
     }
-}
 
-impl Reactor for Publisher {
-    type Input = TcpStream;
-    type Output = ();
+    // Publish the buffered messages and send any
+    // acks that were waiting for them to be published.
+    fn publish(&mut self) {
+        let mut connection_ids = Vec::new();
+        for (connection_id, ack) in self.batch.publish() {
+            self.connections.reply(connection_id, &ack);
+            connection_ids.push(connection_id);
+        }
 
-    fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
-        use Reaction::*;
-        match reaction {
-            Value(stream) => {
-                if let Ok(stream) = ReactiveTcpStream::new(stream) {
-                    self.connections.insert(stream.token(), Connection::new(stream));
+        connection_ids.sort();
+        connection_ids.dedup();
+        for connection_id in connection_ids {
+            self.connections.write(connection_id);
+        }
+    }
+
+    fn handle_message(&mut self, connection_id: Token, publisher: u64, message: PublishRequest) {
+        match message {
+            PublishRequest::Publish(message) => {
+                if let Some(ack) = self.batch.push(connection_id, publisher, message) {
+                    self.connections.reply(connection_id, &ack);
                 }
-                Continue
             }
-
-            Event(event) => {
-                // Timer tick event:
-                if event.token() == self.timer.token() {
-                    // We can ignore the result as it's simply a unit,
-                    // however we should get the result out to make room 
-                    // for the next one.
-                    let _ = self.timer.try_recv();
-
-                    // Only publish if we have actual data
-                    if !self.publish_payload.is_empty() {
-                        self.broadcast.publish(self.publish_payload.take().freeze());
-                    }
+            PublishRequest::Confirm(confirm) => {
+                if let Some(ack) = self.batch.confirm(connection_id, confirm) {
+                    self.connections.reply(connection_id, &ack);
                 }
+            }
+            PublishRequest::Ping(_) => self.connections.reply(connection_id, &Pong { pong: true }),
+            PublishRequest::Pong(_) => {}
+            PublishRequest::Invalid(invalid) => {
+                self.connections.reply(connection_id, &AckMessage::nack(invalid.id, "invalid publish message"));
+            }
+        }
+    }
 
-                // Connection event:
-                if let Some(con) = self.connections.get_mut(&event.token()) {
-                    con.react(event.into()); // Mark the underlying stream as readable / writable
-
-                    // Read messages to publish.
-                    // Note: could simply send an "ack" message for every "\n"
-                    // char, however this ensures that the message is an actual `PubMessage`
-                    while let Some(msg_result) = con.recv::<PubMessage>() {
-                        match msg_result {
-                            Ok(messages) => {
-                                for message in messages {
-                                    if let Ok(bytes) = LineCodec::encode(&message) {
-                                        if bytes.len() > self.publish_payload.remaining_mut() {
-                                            self.publish_payload.reserve(self.buffer_threshold);
-                                        }
-                                        self.publish_payload.put_slice(&bytes);
-                                    }
-
-                                    // ack message
-                                    let _ = LineCodec::encode(AckMessage::new()).map(|payload| con.add_payload(payload));
-                                }
-                            }
-                            Err(_) => {
-                                self.connections.remove(&event.token());
-                                
-                                // Publish the payload
-                                self.broadcast.publish(self.publish_payload.take().freeze());
-                                return Continue
-                            }
-                        }
-                    }
-
-                    // If enough data is buffered then publish the messages.
-                    if self.publish_payload.len() >= self.buffer_threshold {
-                        self.broadcast.publish(self.publish_payload.take().freeze());
-                    }
-
-                    // Write all ack messages
-                    while let Some(wrt_res) = con.write() {
-                        if wrt_res.is_err() {
-                            self.connections.remove(&event.token());
-                            return Continue
-                        }
-                    }
-
-                    Continue
-                } else {
-                    event.into()
+    fn read(&mut self, connection_id: Token) {
+        let publisher = match self.connections.get(connection_id) {
+            Some(con) => con.id(),
+            None => return,
+        };
+
+        // Read messages to publish.
+        // Note: could simply send an "ack" message for every "\n"
+        // char, however this ensures that the message is an actual `PubMessage`
+        // and lets the ack carry the message id.
+        let received = self.connections.recv::<PublishRequest>(connection_id);
+        let mut fatal = false;
+        for message in received.messages {
+            match message {
+                Ok(message) => self.handle_message(connection_id, publisher, message),
+                Err(e) => {
+                    fatal |= e.is_fatal();
+
+                    // nack message
+                    self.connections.reply(connection_id, &AckMessage::nack(None, e.to_string()));
                 }
             }
+        }
 
-            Continue => Continue,
+        if received.closed {
+            self.connections.close(connection_id);
+            
+            // Publish the payload
+            self.publish();
+            return
         }
+
+        // Disconnect clients sending too much garbage or oversized frames.
+        // The nacks written above are flushed first so the
+        // client can tell why.
+        if fatal || self.connections.too_many_malformed_frames(connection_id) {
+            self.connections.write(connection_id);
+            self.connections.close(connection_id);
+            return
+        }
+
+        // While the broadcast is full the publishes are held back,
+        // anything else is still handled. Nothing more is read until
+        // there is room, see `resume`.
+        if self.batch.is_held(connection_id) {
+            self.connections.hold(connection_id);
+        }
+
+        // If enough data is buffered then publish the messages.
+        if self.batch.is_full() {
+            self.publish();
+        }
+
+        // Write all ack messages
+        self.connections.write(connection_id);
+    }
+
+    // Add the publishes held back while the broadcast
+    // was full, once there is room again, and read
+    // their connections again.
+    fn resume(&mut self) {
+        for connection_id in self.batch.resume() {
+            self.connections.release(connection_id);
+            self.read(connection_id);
+        }
+    }
+
+    // Stop reading, publish what is buffered (sending the
+    // held back acks) and let every connection know.
+    // Nothing is read once shutting down, so the batch can be
+    // published and the subscribers told they can finish.
+    fn shutdown(&mut self) {
+        self.publish();
+        self.connections.flush();
+        self.connections.goodbye();
+    }
+
+    fn react_to_event(&mut self, event: Event) -> Reaction<()> {
+        let event = match self.connections.react(event) {
+            Ready::Shutdown => {
+                self.shutdown();
+                return Reaction::Continue
+            }
+            Ready::Read(connection_id) => {
+                self.read(connection_id);
+                return Reaction::Continue
+            }
+            Ready::Handled => return Reaction::Continue,
+            Ready::Other(event) => event,
+        };
+
+        // Timer tick event:
+        if event.token() == self.timer.token() {
+            // We can ignore the result as it's simply a unit,
+            // however we should get the result out to make room 
+            // for the next one.
+            let _ = self.timer.try_recv();
+
+            if self.connections.is_draining() {
+                self.connections.drained();
+            } else {
+                self.resume();
+                self.publish();
+            }
+            return Reaction::Continue
+        }
+
+        event.into()
+    }
+}
+
+impl<C: Codec> Reactor for Publisher<C> {
+    type Input = TcpStream;
+    type Output = ();
+
+    fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
+        let reaction = match reaction {
+            Reaction::Value(stream) => {
+                self.connections.accept(stream);
+                Reaction::Continue
+            }
+            Reaction::Event(event) => self.react_to_event(event),
+            Reaction::Continue => Reaction::Continue,
+        };
+
+        for connection_id in self.connections.closed() {
+            self.batch.remove(connection_id);
+        }
+
+        reaction
     }
 }
@@ -0,0 +1,307 @@
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use sonr::prelude::*;
+use sonr::errors::Result;
+use sonr::sync::signal::{ReactiveSignalReceiver, SignalReceiver, SignalSender};
+use sonr::system::SystemEvent;
+
+/// Tells every thread of the broker to shut down,
+/// draining their connections until the deadline.
+///
+/// Shutting down takes two steps: every publishing reactor first
+/// publishes what it has buffered, and only once all of them have
+/// are the subscribers sent their remaining messages and a goodbye.
+/// Otherwise a subscriber could close before a publisher on another
+/// thread flushed, and the (confirmed) messages would be lost.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    stopped: Arc<AtomicBool>,
+    deadline: Arc<Mutex<Option<Instant>>>,
+    senders: Arc<Mutex<Vec<SignalSender<Signal>>>>,
+    // Publishing reactors yet to flush their batch
+    publishers: Arc<AtomicUsize>,
+    flushed: Arc<AtomicBool>,
+}
+
+#[derive(Clone, Copy)]
+enum Signal {
+    Stop(Instant),
+    Flushed,
+}
+
+impl Shutdown {
+    /// Only the first call has any effect
+    pub fn shutdown(&self, deadline: Instant) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+
+        if let Ok(mut d) = self.deadline.lock() {
+            *d = Some(deadline);
+        }
+
+        self.send(Signal::Stop(deadline));
+
+        // Nothing to wait for
+        if self.publishers.load(Ordering::SeqCst) == 0 {
+            self.send_flushed();
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn send(&self, signal: Signal) {
+        if let Ok(senders) = self.senders.lock() {
+            senders.iter().for_each(|sender| { let _ = sender.send(signal); });
+        }
+    }
+
+    // A publishing reactor has flushed its batch
+    fn flushed(&self) {
+        if self.publishers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.send_flushed();
+        }
+    }
+
+    fn send_flushed(&self) {
+        self.flushed.store(true, Ordering::SeqCst);
+        self.send(Signal::Flushed);
+    }
+
+    // A receiver created after `shutdown` gets the signals right away
+    fn receiver(&self) -> SignalReceiver<Signal> {
+        let receiver = SignalReceiver::bounded(2);
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.push(receiver.sender());
+        }
+
+        if let Ok(deadline) = self.deadline.lock() {
+            if let Some(deadline) = *deadline {
+                let _ = receiver.sender().send(Signal::Stop(deadline));
+            }
+        }
+
+        if self.flushed.load(Ordering::SeqCst) {
+            let _ = receiver.sender().send(Signal::Flushed);
+        }
+
+        receiver
+    }
+}
+
+/// The reactors of a thread that have to drain before
+/// the thread stops. Once the last one is done
+/// the thread's `System` is stopped.
+#[derive(Clone, Default)]
+pub struct DrainGroup {
+    remaining: Rc<Cell<usize>>,
+}
+
+impl DrainGroup {
+    /// Add a reactor to the group
+    pub fn drain(&self, shutdown: &Shutdown) -> Result<Drain> {
+        self.remaining.set(self.remaining.get() + 1);
+        Ok(Drain {
+            signal: ReactiveSignalReceiver::new(shutdown.receiver())?,
+            deadline: None,
+            flushed: false,
+            publisher: None,
+            finished: false,
+            group: self.clone(),
+        })
+    }
+
+    /// Add a reactor that publishes to the group.
+    /// Subscribers wait for it to `flush` before they close.
+    pub fn publisher_drain(&self, shutdown: &Shutdown) -> Result<Drain> {
+        shutdown.publishers.fetch_add(1, Ordering::SeqCst);
+        let mut drain = self.drain(shutdown)?;
+        drain.publisher = Some(shutdown.clone());
+        Ok(drain)
+    }
+}
+
+/// A reactor's part of a shutdown.
+/// On the signal's event the reactor calls `receive`. Once draining
+/// it stops taking new work, a publishing reactor publishes what it has
+/// buffered and calls `flush`. Once every publisher has (`is_flushed`)
+/// the reactor sends what is left and calls `finish` once its
+/// connections are drained (or the deadline has passed).
+pub struct Drain {
+    signal: ReactiveSignalReceiver<Signal>,
+    deadline: Option<Instant>,
+    flushed: bool,
+    // Set until a publishing reactor has flushed
+    publisher: Option<Shutdown>,
+    finished: bool,
+    group: DrainGroup,
+}
+
+impl Drain {
+    pub fn token(&self) -> Token {
+        self.signal.token()
+    }
+
+    /// Take the pending signals
+    pub fn receive(&mut self) {
+        while let Ok(signal) = self.signal.try_recv() {
+            match signal {
+                Signal::Stop(deadline) => self.deadline = self.deadline.or(Some(deadline)),
+                Signal::Flushed => self.flushed = true,
+            }
+        }
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.deadline.is_some()
+    }
+
+    /// Every publisher has flushed its batch,
+    /// or they are out of time
+    pub fn is_flushed(&self) -> bool {
+        self.is_draining() && (self.flushed || self.is_expired())
+    }
+
+    /// The deadline has passed, anything not yet written is lost
+    pub fn is_expired(&self) -> bool {
+        self.deadline.is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// The reactor has published everything it buffered
+    pub fn flush(&mut self) {
+        if let Some(shutdown) = self.publisher.take() {
+            shutdown.flushed();
+        }
+    }
+
+    pub fn finish(&mut self) {
+        // A publisher that never flushed doesn't hold up the others
+        self.flush();
+
+        if self.finished {
+            return;
+        }
+
+        self.finished = true;
+        let remaining = self.group.remaining.get() - 1;
+        self.group.remaining.set(remaining);
+        if remaining == 0 {
+            System::send(SystemEvent::Stop);
+        }
+    }
+}
+
+/// Stops the thread when the broker shuts down,
+/// for threads with nothing to drain (such as the listeners).
+pub struct Stop {
+    drain: Drain,
+}
+
+impl Stop {
+    pub fn new(drain: Drain) -> Self {
+        Self { drain }
+    }
+}
+
+impl Reactor for Stop {
+    type Input = ();
+    type Output = ();
+
+    fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
+        match reaction {
+            Reaction::Event(event) if event.token() == self.drain.token() => {
+                self.drain.receive();
+                if self.drain.is_draining() {
+                    self.drain.finish();
+                }
+                Reaction::Continue
+            }
+            Reaction::Event(event) => Reaction::Event(event),
+            _ => Reaction::Continue,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn deadline() -> Instant {
+        Instant::now() + Duration::from_secs(60)
+    }
+
+    #[test]
+    fn stop_reading() {
+        let shutdown = Shutdown::default();
+        let group = DrainGroup::default();
+        let mut drain = group.drain(&shutdown).unwrap();
+
+        drain.receive();
+        assert!(!drain.is_draining());
+
+        shutdown.shutdown(deadline());
+        drain.receive();
+        assert!(drain.is_draining());
+        assert!(!drain.is_expired());
+
+        // Nothing publishes, so there is nothing to wait for
+        assert!(drain.is_flushed());
+
+        // Created after the shutdown
+        let mut late = group.drain(&shutdown).unwrap();
+        late.receive();
+        assert!(late.is_draining());
+        assert!(late.is_flushed());
+    }
+
+    #[test]
+    fn flushed_once_every_publisher_has() {
+        let shutdown = Shutdown::default();
+        let group = DrainGroup::default();
+        let mut first = group.publisher_drain(&shutdown).unwrap();
+        let mut second = DrainGroup::default().publisher_drain(&shutdown).unwrap();
+        let mut subscriber = group.drain(&shutdown).unwrap();
+
+        shutdown.shutdown(deadline());
+        subscriber.receive();
+        assert!(subscriber.is_draining());
+        assert!(!subscriber.is_flushed());
+
+        first.flush();
+        // Only counted once
+        first.flush();
+        subscriber.receive();
+        assert!(!subscriber.is_flushed());
+
+        second.flush();
+        subscriber.receive();
+        assert!(subscriber.is_flushed());
+        first.receive();
+        assert!(first.is_flushed());
+    }
+
+    #[test]
+    fn flushed_once_the_deadline_passes() {
+        let shutdown = Shutdown::default();
+        let group = DrainGroup::default();
+        let _publisher = group.publisher_drain(&shutdown).unwrap();
+        let mut subscriber = group.drain(&shutdown).unwrap();
+
+        shutdown.shutdown(Instant::now());
+        subscriber.receive();
+        assert!(subscriber.is_expired());
+        assert!(subscriber.is_flushed());
+
+        // Only the first deadline counts
+        shutdown.shutdown(deadline());
+        subscriber.receive();
+        assert!(subscriber.is_expired());
+    }
+}
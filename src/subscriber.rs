@@ -1,139 +1,259 @@
-use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::atomic::Ordering;
 
-use sonr::Token;
+use sonr::{Event, Token};
 use sonr::reactor::{Reactor, Reaction};
 use sonr::errors::Result;
-use sonr::net::tcp::{TcpStream, ReactiveTcpStream};
-use sonr::sync::signal::{SignalReceiver, ReactiveSignalReceiver};
-use bytes::{Bytes, BytesMut, BufMut};
-
-use crate::connection::Connection;
-use crate::codec::LineCodec;
-use crate::messages::{PubMessage, Subscribe};
-use crate::BUFFER_SIZE;
-
-pub struct Subscriber {
-    connections: HashMap<Token, Connection>,
-    messages: ReactiveSignalReceiver<Bytes>,
-    channels: HashMap<String, Vec<Token>>,
-    message_buffer: BytesMut,
+use sonr::net::tcp::TcpStream;
+
+use crate::batch::BatchReceiver;
+use crate::broadcast::BroadcastReceiver;
+use crate::connection::Delivery;
+use crate::connections::{Connections, Ready};
+use crate::codec::Codec;
+use crate::messages::{
+    ErrorCode, ErrorMessage, ListSubscriptions, Pong, SubscribeAck, SubscriberMessage, SubscriberReply,
+    SubscriptionList, UnsubscribeAck, UnsubscribeAll,
+};
+use crate::metrics::METRICS;
+use crate::options::Options;
+use crate::shutdown::Drain;
+use crate::subscriptions::{normalize_pattern, Subscriptions};
+use crate::timer::TimerNotifier;
+
+pub struct Subscriber<C: Codec> {
+    connections: Connections<C>,
+    messages: BatchReceiver,
+    subscriptions: Subscriptions,
 }
 
-impl Subscriber {
-    pub fn new(messages: SignalReceiver<Bytes>) -> Result<Self> {
+impl<C: Codec> Subscriber<C> {
+    pub fn new(messages: BroadcastReceiver, heartbeat: TimerNotifier, drain: Drain, options: Options) -> Result<Self> {
+        let messages = BatchReceiver::new(messages)?;
         Ok(Self {
-            connections: HashMap::new(),
-            messages: ReactiveSignalReceiver::new(messages)?,
-            channels: HashMap::new(),
-            message_buffer: BytesMut::with_capacity(BUFFER_SIZE),
+            connections: Connections::new(heartbeat, drain, options)?,
+            subscriptions: Subscriptions::with_interest(messages.interest()),
+            messages,
         })
     }
 
     fn publish(&mut self) {
-        loop {
-            let message = LineCodec::decode::<PubMessage>(&mut self.message_buffer);
-            if message.is_none() { return }
-
-            let message = message.unwrap();
-            if let Some(connection_ids) = self.channels.get(&message.channel) {
-                let connection_ids = connection_ids.clone();
-
-                if let Ok(encoded_message) = LineCodec::encode(message) {
-                    for cid in connection_ids {
-                        if let Some(con) = self.connections.get_mut(&cid) {
-                            con.add_payload(encoded_message.clone());
-
-                            while let Some(wrt_res) = con.write() {
-                                if wrt_res.is_err() {
-                                    self.connections.remove(&cid);
-                                    self.unsubscribe(cid);
-                                    break;
-                                }
-                            }
-                        }
+        let mut connection_ids = HashSet::new();
+        let mut overflowed = HashSet::new();
+        while let Some(message) = self.messages.next_message() {
+            let subscribers = self.subscriptions.subscribers(message.channel());
+            if subscribers.is_empty() {
+                continue;
+            }
+
+            if let Some(encoded_message) = message.encode::<C>() {
+                for &cid in subscribers {
+                    // Closed below, once every message has been handled
+                    if overflowed.contains(&cid) {
+                        continue;
+                    }
+
+                    if let Some(con) = self.connections.get_mut(cid) {
+                        match con.deliver(encoded_message.clone()) {
+                            Delivery::Overflow => overflowed.insert(cid),
+                            _ => connection_ids.insert(cid),
+                        };
                     }
                 }
             }
         }
+
+        for connection_id in overflowed {
+            connection_ids.remove(&connection_id);
+            self.connections.disconnect(connection_id, ErrorCode::SlowConsumer);
+            METRICS.slow_consumer_disconnects.fetch_add(1, Ordering::Relaxed);
+        }
+
+        for connection_id in connection_ids {
+            self.connections.write(connection_id);
+        }
+    }
+
+    // Called at each stage of the shutdown. Once draining nothing is
+    // read, messages keep being delivered as they arrive. Once every
+    // publisher has flushed, the rest of the broadcast is delivered
+    // and every connection sent a goodbye. They close once everything
+    // is written, or when the deadline passes.
+    fn shutdown(&mut self) {
+        if !self.connections.is_flushed() {
+            return
+        }
+
+        if self.messages.receive(Reaction::Continue) {
+            self.publish();
+        }
+
+        self.connections.goodbye();
     }
 
-    fn unsubscribe(&mut self, connection_id: Token) {
-        for connection_ids in self.channels.values_mut() {
-            while let Some(pos) = connection_ids.iter().position(|id| id == &connection_id) {
-                connection_ids.remove(pos);
+    fn read(&mut self, connection_id: Token) {
+        // Read all "subscribe" / "unsubscribe" messages
+        let received = self.connections.recv::<SubscriberMessage>(connection_id);
+        let mut fatal = false;
+        for message in received.messages {
+            match message {
+                Ok(message) => {
+                    if let Some(reply) = handle_subscription(&mut self.subscriptions, connection_id, message) {
+                        self.connections.reply(connection_id, &reply);
+                    }
+                }
+                Err(e) => {
+                    fatal |= e.is_fatal();
+                    self.connections.reply(connection_id, &ErrorMessage::from(&e));
+                }
             }
         }
+
+        if received.closed {
+            self.connections.close(connection_id);
+            return
+        }
+
+        // Disconnect clients sending too much garbage,
+        // after letting them know why.
+        if self.connections.too_many_malformed_frames(connection_id) {
+            self.connections.disconnect(connection_id, ErrorCode::TooManyMalformedFrames);
+            return
+        }
+
+        if fatal {
+            self.connections.write(connection_id);
+            self.connections.close(connection_id);
+            return
+        }
+
+        // Write replies and any data left over from a previous publish
+        self.connections.write(connection_id);
     }
 
-    fn add_payload(&mut self, payload: Bytes) {
-        if payload.len() > self.message_buffer.remaining_mut() {
-            self.message_buffer.reserve(payload.len());
+    fn react_to_event(&mut self, event: Event) -> Reaction<()> {
+        let event = match self.connections.react(event) {
+            Ready::Shutdown => {
+                self.shutdown();
+                return Reaction::Continue
+            }
+            Ready::Read(connection_id) => {
+                self.read(connection_id);
+                return Reaction::Continue
+            }
+            Ready::Handled => return Reaction::Continue,
+            Ready::Other(event) => event,
+        };
+
+        // Incoming messages:
+        if event.token() == self.messages.token() {
+            if !self.connections.is_closing() && self.messages.receive(event.into()) {
+                self.publish();
+            }
+            return Reaction::Continue
         }
-        self.message_buffer.put_slice(&payload);
+
+        event.into()
     }
 }
 
-impl Reactor for Subscriber {
+/// Apply a subscriber message to the subscriptions of a connection,
+/// returning the reply for the connection, if any.
+pub(crate) fn handle_subscription(
+    subscriptions: &mut Subscriptions,
+    connection_id: Token,
+    message: SubscriberMessage,
+) -> Option<SubscriberReply> {
+    let reply = match message {
+        SubscriberMessage::Subscribe(subscribe) => {
+            if normalize_pattern(&subscribe.channel).is_none() {
+                let reason = format!("invalid channel: {}", subscribe.channel);
+                return Some(SubscriberReply::Error(ErrorMessage::new(ErrorCode::InvalidChannel, reason)));
+            }
+
+            // Subscribing twice is a no-op
+            subscriptions.subscribe(connection_id, &subscribe.channel);
+            SubscriberReply::Subscribed(SubscribeAck::new(subscribe.channel))
+        }
+        SubscriberMessage::Unsubscribe(unsubscribe) => {
+            let mut unsubscribed = Vec::new();
+            if subscriptions.unsubscribe(connection_id, &unsubscribe.channel) {
+                unsubscribed.push(unsubscribe.channel);
+            }
+            SubscriberReply::Unsubscribed(UnsubscribeAck::new(unsubscribed))
+        }
+        SubscriberMessage::UnsubscribeAll(UnsubscribeAll { unsubscribe_all: false }) => {
+            let reason = "unsubscribe_all must be true";
+            return Some(SubscriberReply::Error(ErrorMessage::new(ErrorCode::UnknownMessage, reason)));
+        }
+        SubscriberMessage::UnsubscribeAll(_) => {
+            let unsubscribed = subscriptions.unsubscribe_all(connection_id);
+            SubscriberReply::Unsubscribed(UnsubscribeAck::new(unsubscribed))
+        }
+        SubscriberMessage::ListSubscriptions(ListSubscriptions { list_subscriptions: false }) => {
+            let reason = "list_subscriptions must be true";
+            return Some(SubscriberReply::Error(ErrorMessage::new(ErrorCode::UnknownMessage, reason)));
+        }
+        SubscriberMessage::ListSubscriptions(_) => {
+            let subscriptions = subscriptions.channels(connection_id);
+            SubscriberReply::Subscriptions(SubscriptionList::new(subscriptions))
+        }
+        SubscriberMessage::Ping(_) => SubscriberReply::Pong(Pong { pong: true }),
+        SubscriberMessage::Pong(_) => return None,
+    };
+
+    Some(reply)
+}
+
+impl<C: Codec> Reactor for Subscriber<C> {
     type Input = TcpStream;
     type Output = ();
 
     fn react(&mut self, reaction: Reaction<Self::Input>) -> Reaction<Self::Output> {
-        use Reaction::*;
-        match reaction {
-            Value(stream) => {
-                if let Ok(stream) = ReactiveTcpStream::new(stream) {
-                    self.connections.insert(stream.token(), Connection::new(stream));
-                }
-                Continue
+        let reaction = match reaction {
+            Reaction::Value(stream) => {
+                self.connections.accept(stream);
+                Reaction::Continue
             }
-            Event(event) => {
-                // Incoming messages:
-                if self.messages.token() == event.token() {
-                    if let Value(messages) = self.messages.react(event.into()) {
-                        self.add_payload(messages);
-                        
-                        // Keep "reacting" until we no longer receive a message
-                        while let Value(messages) = self.messages.react(Continue) {
-                            self.add_payload(messages);
-                        }
-
-                        self.publish();
-                    }
-                    return Continue
-                }
+            Reaction::Event(event) => self.react_to_event(event),
+            Reaction::Continue => Reaction::Continue,
+        };
 
-                // Connection event:
-                if let Some(con) = self.connections.get_mut(&event.token()) {
-                    con.react(event.into());
-
-                    // Read all "subscribe" messages
-                    while let Some(messages) = con.recv::<Subscribe>() {
-                        match messages {
-                            Ok(messages) => {
-                                for message in messages {
-                                    match self.channels.get_mut(&message.channel) {
-                                        Some(_) => {
-                                            if let Some(conneciton_ids) = self.channels.get_mut(&message.channel) {
-                                                conneciton_ids.push(event.token());
-                                            }
-                                        }
-                                        None => { self.channels.insert(message.channel, vec![event.token()]); }
-                                    }
-                                }
-                            }
-                            Err(_) => {
-                                self.connections.remove(&event.token());
-                                self.unsubscribe(event.token());
-                                return Continue
-                            }
-                        }
-                    }
-                    Continue
-                } else {
-                    event.into()
-                }
+        for connection_id in self.connections.closed() {
+            self.subscriptions.unsubscribe_all(connection_id);
+        }
+
+        reaction
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handle(subscriptions: &mut Subscriptions, message: &str) -> Option<SubscriberReply> {
+        let message = serde_json::from_str(message).unwrap();
+        handle_subscription(subscriptions, Token(1), message)
+    }
+
+    #[test]
+    fn false_flags_are_rejected() {
+        let mut subscriptions = Subscriptions::new();
+        handle(&mut subscriptions, r#"{"channel": "orders"}"#);
+
+        for message in &[r#"{"unsubscribe_all": false}"#, r#"{"list_subscriptions": false}"#] {
+            match handle(&mut subscriptions, message) {
+                Some(SubscriberReply::Error(err)) => assert_eq!(err.code, ErrorCode::UnknownMessage),
+                reply => panic!("expected an error, got {:?}", reply),
             }
-            Continue => Continue,
+        }
+
+        // Still subscribed
+        assert_eq!(subscriptions.channels(Token(1)), vec!["orders".to_string()]);
+
+        match handle(&mut subscriptions, r#"{"unsubscribe_all": true}"#) {
+            Some(SubscriberReply::Unsubscribed(ack)) => assert_eq!(ack.unsubscribed, vec!["orders".to_string()]),
+            reply => panic!("expected an unsubscribe ack, got {:?}", reply),
         }
     }
 }
@@ -0,0 +1,424 @@
+use std::collections::{HashMap, HashSet};
+use std::str::Split;
+use std::sync::{Arc, RwLock};
+
+use sonr::Token;
+
+const SEPARATOR: char = '.';
+
+// Matches exactly one segment: `orders.*.created`
+const SINGLE_LEVEL: &str = "*";
+const SINGLE_LEVEL_ALIAS: &str = "+";
+
+// Matches one or more trailing segments: `orders.>`
+const MULTI_LEVEL: &str = ">";
+const MULTI_LEVEL_ALIAS: &str = "#";
+
+/// Normalize a subscription pattern so that `+` and `#`
+/// share trie nodes with `*` and `>`.
+/// Returns `None` if the pattern is invalid: empty segments
+/// or a multi-level wildcard that is not the last segment.
+pub fn normalize_pattern(pattern: &str) -> Option<String> {
+    let segments = pattern.split(SEPARATOR).collect::<Vec<_>>();
+    let last = segments.len() - 1;
+    let mut normalized = Vec::with_capacity(segments.len());
+
+    for (i, segment) in segments.into_iter().enumerate() {
+        let segment = match segment {
+            "" => return None,
+            SINGLE_LEVEL_ALIAS => SINGLE_LEVEL,
+            MULTI_LEVEL_ALIAS => MULTI_LEVEL,
+            s => s,
+        };
+
+        if segment == MULTI_LEVEL && i != last {
+            return None;
+        }
+
+        normalized.push(segment);
+    }
+
+    Some(normalized.join(&SEPARATOR.to_string()))
+}
+
+#[derive(Default)]
+struct Node {
+    subscribers: HashSet<Token>,
+    children: HashMap<String, Node>,
+}
+
+impl Node {
+    fn insert(&mut self, segments: &[&str], connection_id: Token) {
+        match segments.split_first() {
+            None => { self.subscribers.insert(connection_id); }
+            Some((segment, rest)) => {
+                self.children
+                    .entry(segment.to_string())
+                    .or_default()
+                    .insert(rest, connection_id);
+            }
+        }
+    }
+
+    // Remove the connection, pruning any node left without
+    // subscribers or children.
+    fn remove(&mut self, segments: &[&str], connection_id: Token) {
+        match segments.split_first() {
+            None => { self.subscribers.remove(&connection_id); }
+            Some((segment, rest)) => {
+                if let Some(child) = self.children.get_mut(*segment) {
+                    child.remove(rest, connection_id);
+                    if child.is_empty() {
+                        self.children.remove(*segment);
+                    }
+                }
+            }
+        }
+    }
+
+    // The segments are split as they are matched,
+    // rather than collected for every message.
+    fn collect(&self, mut segments: Split<char>, matches: &mut HashSet<Token>) {
+        match segments.next() {
+            None => matches.extend(&self.subscribers),
+            Some(segment) => {
+                if let Some(child) = self.children.get(segment) {
+                    child.collect(segments.clone(), matches);
+                }
+
+                if let Some(child) = self.children.get(SINGLE_LEVEL) {
+                    child.collect(segments, matches);
+                }
+
+                if let Some(child) = self.children.get(MULTI_LEVEL) {
+                    matches.extend(&child.subscribers);
+                }
+            }
+        }
+    }
+
+    fn matches(&self, mut segments: Split<char>) -> bool {
+        match segments.next() {
+            None => !self.subscribers.is_empty(),
+            Some(segment) => {
+                self.children.get(segment).is_some_and(|child| child.matches(segments.clone()))
+                    || self.children.get(SINGLE_LEVEL).is_some_and(|child| child.matches(segments))
+                    || self.children.get(MULTI_LEVEL).is_some_and(|child| !child.subscribers.is_empty())
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.subscribers.is_empty() && self.children.is_empty()
+    }
+}
+
+/// Keeps track of which connections are subscribed to which channels.
+///
+/// Channels are hierarchical, separated by `.`, and subscriptions can use
+/// wildcards: `*` (or `+`) matches a single segment and `>` (or `#`)
+/// matches all remaining segments.
+///
+/// Subscriptions are stored in a trie (used when publishing) and by
+/// connection (used when a connection leaves).
+pub struct Subscriptions {
+    root: Node,
+    connections: HashMap<Token, HashSet<String>>,
+    interest: Interest,
+    // Reused by `subscribers`
+    matches: HashSet<Token>,
+}
+
+impl Subscriptions {
+    pub fn new() -> Self {
+        Self::with_interest(Interest::default())
+    }
+
+    /// Keep `interest` up to date with the patterns subscribed to
+    pub fn with_interest(interest: Interest) -> Self {
+        Self {
+            root: Node::default(),
+            connections: HashMap::new(),
+            interest,
+            matches: HashSet::new(),
+        }
+    }
+
+    /// Subscribe a connection to a channel pattern.
+    /// Returns false if the connection was already subscribed
+    /// or the pattern is invalid.
+    pub fn subscribe(&mut self, connection_id: Token, pattern: &str) -> bool {
+        let pattern = match normalize_pattern(pattern) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        let patterns = self.connections.entry(connection_id).or_default();
+        if patterns.contains(&pattern) {
+            return false;
+        }
+
+        self.root.insert(&segments(&pattern), connection_id);
+        self.interest.add(&pattern);
+        patterns.insert(pattern);
+        true
+    }
+
+    /// Unsubscribe a connection from a channel pattern.
+    /// Returns false if the connection was not subscribed.
+    pub fn unsubscribe(&mut self, connection_id: Token, pattern: &str) -> bool {
+        let pattern = match normalize_pattern(pattern) {
+            Some(p) => p,
+            None => return false,
+        };
+
+        let removed = match self.connections.get_mut(&connection_id) {
+            Some(patterns) => {
+                let removed = patterns.remove(&pattern);
+                if patterns.is_empty() {
+                    self.connections.remove(&connection_id);
+                }
+                removed
+            }
+            None => false,
+        };
+
+        if removed {
+            self.root.remove(&segments(&pattern), connection_id);
+            self.interest.remove(&pattern);
+        }
+
+        removed
+    }
+
+    /// Unsubscribe a connection from every channel pattern,
+    /// returning the patterns it was subscribed to.
+    pub fn unsubscribe_all(&mut self, connection_id: Token) -> Vec<String> {
+        let patterns = match self.connections.remove(&connection_id) {
+            Some(patterns) => patterns,
+            None => return Vec::new(),
+        };
+
+        for pattern in &patterns {
+            self.root.remove(&segments(pattern), connection_id);
+            self.interest.remove(pattern);
+        }
+
+        patterns.into_iter().collect()
+    }
+
+    /// All connections with at least one pattern matching the channel.
+    /// A connection is only included once, no matter how many
+    /// of its patterns match.
+    /// The set is reused by the next call, rather than
+    /// allocated for every delivered message.
+    pub fn subscribers(&mut self, channel: &str) -> &HashSet<Token> {
+        self.matches.clear();
+        self.root.collect(channel.split(SEPARATOR), &mut self.matches);
+        &self.matches
+    }
+
+    /// All channel patterns a connection is subscribed to.
+    pub fn channels(&self, connection_id: Token) -> Vec<String> {
+        self.connections
+            .get(&connection_id)
+            .map(|patterns| patterns.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Every pattern in the interest trie is held by the same token
+const INTEREST: Token = Token(0);
+
+/// The channel patterns subscribed to by any connection of a reactor.
+/// Shared with the publishers, so they only send a reactor
+/// the messages it has subscribers for.
+#[derive(Clone, Default)]
+pub struct Interest {
+    inner: Arc<RwLock<InterestPatterns>>,
+}
+
+#[derive(Default)]
+struct InterestPatterns {
+    root: Node,
+    // Number of connections subscribed to each pattern
+    patterns: HashMap<String, usize>,
+}
+
+impl Interest {
+    /// At least one pattern matches the channel
+    pub fn matches(&self, channel: &str) -> bool {
+        match self.inner.read() {
+            Ok(inner) => inner.root.matches(channel.split(SEPARATOR)),
+            Err(_) => true,
+        }
+    }
+
+    // Patterns are normalized by `Subscriptions`
+    pub(crate) fn add(&self, pattern: &str) {
+        if let Ok(mut inner) = self.inner.write() {
+            let count = inner.patterns.entry(pattern.to_string()).or_insert(0);
+            *count += 1;
+            if *count == 1 {
+                inner.root.insert(&segments(pattern), INTEREST);
+            }
+        }
+    }
+
+    pub(crate) fn remove(&self, pattern: &str) {
+        if let Ok(mut inner) = self.inner.write() {
+            let count = match inner.patterns.get_mut(pattern) {
+                Some(count) => {
+                    *count -= 1;
+                    *count
+                }
+                None => return,
+            };
+
+            if count == 0 {
+                inner.patterns.remove(pattern);
+                inner.root.remove(&segments(pattern), INTEREST);
+            }
+        }
+    }
+}
+
+fn segments(channel: &str) -> Vec<&str> {
+    channel.split(SEPARATOR).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscribers(subscriptions: &mut Subscriptions, channel: &str) -> Vec<Token> {
+        let mut tokens = subscriptions.subscribers(channel).iter().cloned().collect::<Vec<_>>();
+        tokens.sort();
+        tokens
+    }
+
+    #[test]
+    fn normalize() {
+        assert_eq!(normalize_pattern("orders.created"), Some("orders.created".into()));
+        assert_eq!(normalize_pattern("orders.+.#"), Some("orders.*.>".into()));
+        assert_eq!(normalize_pattern(">"), Some(">".into()));
+
+        // Empty segments
+        assert_eq!(normalize_pattern(""), None);
+        assert_eq!(normalize_pattern("orders..created"), None);
+        assert_eq!(normalize_pattern("orders."), None);
+        assert_eq!(normalize_pattern(".orders"), None);
+
+        // `>` not last
+        assert_eq!(normalize_pattern("orders.>.created"), None);
+        assert_eq!(normalize_pattern("#.created"), None);
+    }
+
+    #[test]
+    fn wildcards() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(Token(1), "orders.*");
+        subscriptions.subscribe(Token(2), "orders.>");
+        subscriptions.subscribe(Token(3), "orders.*.created");
+
+        assert_eq!(subscribers(&mut subscriptions, "orders.eu"), vec![Token(1), Token(2)]);
+        assert_eq!(subscribers(&mut subscriptions, "orders.eu.created"), vec![Token(2), Token(3)]);
+        assert_eq!(subscribers(&mut subscriptions, "orders.eu.created.late"), vec![Token(2)]);
+
+        // `>` matches one or more segments
+        assert_eq!(subscribers(&mut subscriptions, "orders"), vec![]);
+        assert_eq!(subscribers(&mut subscriptions, "users.eu"), vec![]);
+    }
+
+    #[test]
+    fn aliases_share_patterns() {
+        let mut subscriptions = Subscriptions::new();
+        assert!(subscriptions.subscribe(Token(1), "orders.+"));
+        assert!(!subscriptions.subscribe(Token(1), "orders.*"));
+        assert!(subscriptions.subscribe(Token(1), "orders.#"));
+        assert!(!subscriptions.subscribe(Token(1), "orders..created"));
+
+        assert_eq!(subscriptions.channels(Token(1)).len(), 2);
+        assert!(subscriptions.unsubscribe(Token(1), "orders.*"));
+        assert!(subscriptions.unsubscribe(Token(1), "orders.>"));
+    }
+
+    #[test]
+    fn connection_included_once() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(Token(1), "orders.created");
+        subscriptions.subscribe(Token(1), "orders.*");
+        subscriptions.subscribe(Token(1), "orders.>");
+
+        assert_eq!(subscribers(&mut subscriptions, "orders.created"), vec![Token(1)]);
+    }
+
+    #[test]
+    fn unsubscribe_prunes() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(Token(1), "orders.eu.created");
+        subscriptions.subscribe(Token(2), "orders.eu.created");
+        subscriptions.subscribe(Token(2), "orders.*");
+
+        assert!(subscriptions.unsubscribe(Token(1), "orders.eu.created"));
+        assert!(!subscriptions.unsubscribe(Token(1), "orders.eu.created"));
+        assert_eq!(subscribers(&mut subscriptions, "orders.eu.created"), vec![Token(2)]);
+
+        assert!(subscriptions.unsubscribe(Token(2), "orders.eu.created"));
+        let orders = &subscriptions.root.children["orders"];
+        assert!(!orders.children.contains_key("eu"));
+
+        assert!(subscriptions.unsubscribe(Token(2), "orders.*"));
+        assert!(subscriptions.root.is_empty());
+        assert!(subscriptions.connections.is_empty());
+    }
+
+    #[test]
+    fn unsubscribe_all() {
+        let mut subscriptions = Subscriptions::new();
+        subscriptions.subscribe(Token(1), "orders.*");
+        subscriptions.subscribe(Token(1), "users.>");
+        subscriptions.subscribe(Token(2), "users.>");
+
+        let mut unsubscribed = subscriptions.unsubscribe_all(Token(1));
+        unsubscribed.sort();
+        assert_eq!(unsubscribed, vec!["orders.*".to_string(), "users.>".to_string()]);
+        assert!(subscriptions.unsubscribe_all(Token(1)).is_empty());
+        assert!(subscriptions.channels(Token(1)).is_empty());
+
+        assert_eq!(subscribers(&mut subscriptions, "orders.eu"), vec![]);
+        assert_eq!(subscribers(&mut subscriptions, "users.eu"), vec![Token(2)]);
+        assert!(!subscriptions.root.children.contains_key("orders"));
+    }
+
+    #[test]
+    fn interest_counts_connections() {
+        let interest = Interest::default();
+        let mut subscriptions = Subscriptions::with_interest(interest.clone());
+        assert!(!interest.matches("orders.eu"));
+
+        subscriptions.subscribe(Token(1), "orders.*");
+        subscriptions.subscribe(Token(2), "orders.+");
+        assert!(interest.matches("orders.eu"));
+        assert!(!interest.matches("orders.eu.created"));
+
+        // Still held by the other connection
+        subscriptions.unsubscribe(Token(1), "orders.*");
+        assert!(interest.matches("orders.eu"));
+
+        subscriptions.unsubscribe_all(Token(2));
+        assert!(!interest.matches("orders.eu"));
+        assert!(interest.inner.read().unwrap().patterns.is_empty());
+        assert!(interest.inner.read().unwrap().root.is_empty());
+
+        // Unknown patterns are ignored
+        interest.remove("orders.*");
+        assert!(!interest.matches("orders.eu"));
+    }
+}
@@ -1,4 +1,6 @@
 use sonr::sync::signal::{SignalSender, SignalReceiver, ReactiveSignalReceiver};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
 use std::time::Duration;
 
@@ -8,6 +10,7 @@ pub type TimerNotifier = SignalReceiver<()>;
 pub struct Timer {
     senders: Vec<SignalSender<()>>,
     interval: Duration,
+    stopped: Arc<AtomicBool>,
 }
 
 impl Timer {
@@ -15,6 +18,7 @@ impl Timer {
         Self { 
             senders: Vec::new(),
             interval,
+            stopped: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -24,9 +28,14 @@ impl Timer {
         receiver
     }
 
+    /// Setting the flag stops the timer thread on its next tick
+    pub fn stop_flag(&self) -> Arc<AtomicBool> {
+        self.stopped.clone()
+    }
+
     pub fn start(self) {
         thread::spawn(move || {
-            loop {
+            while !self.stopped.load(Ordering::SeqCst) {
                 thread::sleep(self.interval);
                 self.senders.iter().for_each(|n| { let _ = n.send(()); } );
             }